@@ -1,27 +1,161 @@
 //! Communicates with the audio playback engine.
 
+use crate::config::{self, ReplayGain};
+use crate::crossfade::Crossfader;
+use crate::equalizer::{self, Equalizer};
+use crate::loudness;
+use crate::seek::{Acceleration, Seek};
+use crate::sink;
 use crate::song::Song;
 use crate::traits::{AudioPlaybin, PathToURI, UnwrappedMutex};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use glib::{source::Priority, MainContext};
 use gst::prelude::*;
+use gst_audio::{prelude::*, StreamVolumeFormat};
+use std::collections::VecDeque;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 /// The internal state of the playback backend
 pub struct Backend {
     playbin: gst::Element,
-    next_uri: Arc<Mutex<Option<String>>>,
+    /// ReplayGain volume element, None if ReplayGain is off or the
+    /// element is not installed
+    rgvolume: Option<gst::Element>,
+    /// `equalizer-10bands` element, None if it is not installed
+    equalizer: Option<gst::Element>,
+    /// Presets and gains of the equalizer
+    eq: Arc<Mutex<Equalizer>>,
+    /// Whether `scaletempo` is in the audio filter. Without it, the
+    /// playback rate cannot be changed without changing the pitch.
+    scaletempo: bool,
+    /// Loudness of files without ReplayGain tags, see `fallback_gain()`
+    loudness: Arc<Mutex<loudness::Cache>>,
+    /// Fallback gains of the songs handed to playbin, in order, taken
+    /// by `rgvolume` when each of them starts
+    gains: Arc<Mutex<VecDeque<f64>>>,
+    /// Song being played, and the one to be played next
+    current: Arc<Mutex<Option<Track>>>,
+    next: Arc<Mutex<Option<Track>>>,
+    /// True from handing the next song to playbin for a gapless switch
+    /// until it starts playing
+    switching: Arc<Mutex<bool>>,
+    /// Mixes the end of one song with the start of the next, None if
+    /// crossfading is off
+    crossfader: Option<Arc<Mutex<Crossfader>>>,
+    /// Playback rate, 1.0 being normal speed
+    rate: Arc<Mutex<f64>>,
+    /// Grows the seek step while a seek key is held down
+    acceleration: Arc<Mutex<Acceleration>>,
+    seeking: Arc<Mutex<Seeking>>,
+    /// A–B loop within the song being played, None if there is none
+    ab_loop: Arc<Mutex<Option<AbLoop>>>,
+    /// True from sending the end of the stream through playbin to
+    /// finish a WAV file until it has arrived, see `stop()`
+    stopping: Arc<Mutex<bool>>,
+    /// Sleep timer, None if it is off
+    sleep: Arc<Mutex<Option<SleepTimer>>>,
+    /// Whether playback stops after the current song or album
+    stop_after: Arc<Mutex<Option<StopAfter>>>,
     main_tx: glib::Sender<BackendMessage>,
 }
 
+/// Seeks requested while playbin is still carrying out an earlier one,
+/// so that key repeats do not pile up flushing seeks.
+#[derive(Debug, Default)]
+struct Seeking {
+    /// Position playbin is seeking to, None if it is not seeking
+    target: Option<gst::ClockTime>,
+    /// Position to seek to once it is done, the last one requested
+    pending: Option<gst::ClockTime>,
+}
+
+/// Points of a loop within a song, for practising along with it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AbLoop {
+    pub a: Duration,
+    /// Point B, None until it is marked and the loop starts
+    pub b: Option<Duration>,
+}
+
+/// Counts down to stopping playback, fading it out at the end.
+#[derive(Clone, Copy, Debug)]
+struct SleepTimer {
+    /// Length chosen, see `SLEEP_TIMES`
+    length: Duration,
+    deadline: Instant,
+    /// Volume before the fade out started, None until it has
+    volume: Option<u8>,
+}
+
+impl SleepTimer {
+    fn new(length: Duration) -> Self {
+        Self {
+            length,
+            deadline: Instant::now() + length,
+            volume: None,
+        }
+    }
+}
+
+/// Where playback is to stop instead of going on to the next song.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopAfter {
+    Song,
+    Album,
+}
+
+/// A song handed to playbin.
+#[derive(Clone)]
+struct Track {
+    path: PathBuf,
+    uri: String,
+    /// Gain if the song has no ReplayGain tags, see `fallback_gain()`
+    gain: f64,
+    /// Album of the song, see `Song::album_info`
+    album: String,
+}
+
+/// Slowest playback rate
+const MIN_RATE: f64 = 0.5;
+/// Fastest playback rate
+const MAX_RATE: f64 = 3.0;
+
+/// How long before a crossfade the song fading out starts being
+/// decoded, see `check_crossfade()`
+const CROSSFADE_PREROLL: Duration = Duration::from_secs(5);
+
+/// Lengths of the sleep timer to cycle through, see `cycle_sleep_timer()`
+const SLEEP_TIMES: [Duration; 3] = [
+    Duration::from_secs(15 * 60),
+    Duration::from_secs(30 * 60),
+    Duration::from_secs(60 * 60),
+];
+/// How long playback fades out for before the sleep timer stops it
+const SLEEP_FADE: Duration = Duration::from_secs(30);
+
 /// State of playback
 pub struct BackendState {
+    /// Position in the track, regardless of the playback rate
     pub position: std::time::Duration,
     pub playing: bool,
     pub muted: bool,
+    /// Volume in percent, on a cubic scale
+    pub volume: u8,
+    /// Playback rate, 1.0 being normal speed
+    pub rate: f64,
+    /// ReplayGain adjustment applied to the current song in dB,
+    /// None if ReplayGain is off
+    pub replaygain: Option<f64>,
+    /// Equalizer preset and gains, None if there is no equalizer
+    pub equalizer: Option<equalizer::Settings>,
+    pub ab_loop: Option<AbLoop>,
+    /// Time left until the sleep timer stops playback
+    pub sleep: Option<Duration>,
+    pub stop_after: Option<StopAfter>,
 }
 
 /// Message from `Backend` to the main application
@@ -29,43 +163,112 @@ pub enum BackendMessage {
     ReachedEndOfSong,
     ReachedEndOfPlaylist,
     RequestNextSong,
+    /// The song at the path could not be played, with the error from
+    /// GStreamer
+    Error(PathBuf, String),
     State(BackendState),
 }
 
 impl Backend {
     /// Initializes the GStreamer backend and sets up signal
     /// handling. Returns a tupe with the new Backend object and
-    /// a Receiver for communication during playback.
-    pub fn new() -> (Self, glib::Receiver<BackendMessage>) {
+    /// a Receiver for communication during playback. Files without
+    /// ReplayGain tags are played with their gain from `loudness`.
+    pub fn new(loudness: Arc<Mutex<loudness::Cache>>) -> (Self, glib::Receiver<BackendMessage>) {
         gst::init().expect("Unable to initialize GStreamer");
         let playbin = gst::ElementFactory::make("playbin", None)
             .expect("Unable to create the `playbin` element");
         playbin.disable_video().ok(); // .ok() to ignore any errors
+        let crossfader = match Self::setup_sink(&playbin) {
+            Ok(crossfader) => crossfader.map(|c| Arc::new(Mutex::new(c))),
+            Err(e) => {
+                eprintln!("{:#}", e);
+                std::process::exit(1);
+            }
+        };
+        let gains = Arc::new(Mutex::new(VecDeque::new()));
+        let (rgvolume, equalizer, scaletempo) = match Self::setup_filters(&playbin, &gains) {
+            Ok(elements) => elements,
+            Err(e) => {
+                glib::g_warning!("backend", "Audio filters disabled: {}", e);
+                (None, None, false)
+            }
+        };
 
         // Asynchronous channel to communicate with main() with
         let (main_tx, main_rx) = MainContext::channel(Priority::default());
+
+        let this = Self {
+            playbin,
+            rgvolume,
+            equalizer,
+            eq: Arc::new(Mutex::new(Equalizer::load())),
+            scaletempo,
+            loudness,
+            gains,
+            current: Arc::new(Mutex::new(None)),
+            next: Arc::new(Mutex::new(None)),
+            switching: Arc::new(Mutex::new(false)),
+            crossfader,
+            rate: Arc::new(Mutex::new(1.0)),
+            acceleration: Arc::new(Mutex::new(Acceleration::default())),
+            seeking: Arc::new(Mutex::new(Seeking::default())),
+            ab_loop: Arc::new(Mutex::new(None)),
+            stopping: Arc::new(Mutex::new(false)),
+            sleep: Arc::new(Mutex::new(config::get().sleep.map(SleepTimer::new))),
+            stop_after: Arc::new(Mutex::new(None)),
+            main_tx,
+        };
+
         // Handle messages from GSTreamer bus
-        playbin
+        this.playbin
             .bus()
             .expect("Failed to get GStreamer message bus")
-            .add_watch(glib::clone!(@strong main_tx => move |_bus, msg| {
+            .add_watch(glib::clone!(@strong this => move |_bus, msg| {
                 match msg.view() {
-                    gst::MessageView::Eos(_) =>
-                        main_tx.send(BackendMessage::ReachedEndOfPlaylist)
-                        .expect("Unable to send message to main()"),
-                    gst::MessageView::Error(e) =>
-                        glib::g_debug!("song", "{}", e.error()),
-                        _ => (),
+                    gst::MessageView::Eos(_) => this.reached_end(),
+                    gst::MessageView::Error(e) => this.report_error(e.error().to_string()),
+                    gst::MessageView::StreamStart(_) => {
+                        *this.switching.lockk() = false;
+                        // Songs start at normal speed, so the rate is set again
+                        this.apply_rate();
+                    }
+                    gst::MessageView::SegmentDone(_) => this.repeat_loop(),
+                    gst::MessageView::AsyncDone(_) => {
+                        this.seek_done();
+                        this.apply_rate();
+                    }
+                    _ => (),
                 }
                 glib::Continue(true)
             }))
             .expect("Failed to connect to GStreamer message bus");
 
-        let this = Self {
-            playbin,
-            next_uri: Arc::new(Mutex::new(None)),
-            main_tx,
-        };
+        // With crossfading on, the WAV file is written by the output
+        // pipeline of the crossfader, which is finished on its own
+        if let Some(crossfader) = &this.crossfader {
+            crossfader
+                .lockk()
+                .bus()
+                .expect("Failed to get the crossfader's message bus")
+                .add_watch(glib::clone!(@strong this => move |_bus, msg| {
+                    match msg.view() {
+                        gst::MessageView::Eos(_) | gst::MessageView::Error(_) => {
+                            this.finish_stopping()
+                        }
+                        _ => (),
+                    }
+                    glib::Continue(true)
+                }))
+                .expect("Failed to connect to the crossfader's message bus");
+        }
+
+        let volume = config::get()
+            .volume
+            .or_else(Self::saved_volume)
+            .unwrap_or(100);
+        this.set_volume(volume);
+        this.apply_equalizer(&this.eq.lockk()).ok();
 
         // Switch to next song when reaching end of current track
         this.playbin
@@ -85,6 +288,8 @@ impl Backend {
             glib::clone!(@strong this => move || {
                this.main_tx.send(BackendMessage::State(this.state()))
                    .expect("Unable to send message to main()");
+               this.check_crossfade();
+               this.check_sleep();
             glib::Continue(true)
             }),
         );
@@ -92,6 +297,181 @@ impl Backend {
         (this, main_rx)
     }
 
+    /// Sets the audio output of `playbin` to the one chosen in `Config`,
+    /// if any. Otherwise GStreamer picks one itself. With crossfading
+    /// on, the output goes through the returned `Crossfader` instead.
+    fn setup_sink(playbin: &gst::Element) -> Result<Option<Crossfader>> {
+        let config = config::get();
+        let sink = match config.sink {
+            Some(kind) => Some(sink::make(kind, config.device.as_deref())?),
+            None => None,
+        };
+
+        match (config.crossfade, sink) {
+            (Some(duration), sink) => {
+                let (crossfader, playbin_sink) =
+                    Crossfader::new(duration, sink, Self::filter_description())?;
+                playbin.set_property("audio-sink", &playbin_sink)?;
+                Ok(Some(crossfader))
+            }
+            (None, Some(sink)) => {
+                playbin.set_property("audio-sink", &sink)?;
+                Ok(None)
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Optional elements of the audio filter, in order: ReplayGain,
+    /// unless it is off, the equalizer and `scaletempo`, which keeps the
+    /// pitch when playing faster or slower. Returns each with the
+    /// element factories it needs.
+    fn optional_filters() -> Vec<(&'static str, &'static [&'static str])> {
+        let mut filters: Vec<(&'static str, &'static [&'static str])> = Vec::new();
+        if config::get().replaygain != ReplayGain::Off {
+            filters.push((
+                "rgvolume name=rgvolume ! rglimiter",
+                &["rgvolume", "rglimiter"],
+            ));
+        }
+        filters.push(("equalizer-10bands name=equalizer", &["equalizer-10bands"]));
+        filters.push(("scaletempo name=scaletempo", &["scaletempo"]));
+        filters
+    }
+
+    /// Returns the elements of the audio filter, see `optional_filters()`.
+    /// Elements whose plugin is not installed are left out one by one,
+    /// so that e.g. ReplayGain works without the equalizer.
+    fn filter_description() -> String {
+        let mut description = vec!["audioconvert"];
+        for (elements, factories) in Self::optional_filters() {
+            if factories
+                .iter()
+                .all(|f| gst::ElementFactory::find(f).is_some())
+            {
+                description.push(elements);
+                description.push("audioconvert");
+            }
+        }
+        description.push("audioresample");
+        description.join(" ! ")
+    }
+
+    /// Sets the audio filter of `playbin`, see `filter_description()`.
+    /// Returns the `rgvolume` and `equalizer-10bands` elements, and
+    /// whether `scaletempo` is in the filter.
+    #[allow(clippy::type_complexity)]
+    fn setup_filters(
+        playbin: &gst::Element,
+        gains: &Arc<Mutex<VecDeque<f64>>>,
+    ) -> Result<(Option<gst::Element>, Option<gst::Element>, bool)> {
+        for (_, factories) in Self::optional_filters() {
+            for factory in factories {
+                if gst::ElementFactory::find(factory).is_none() {
+                    glib::g_warning!("backend", "`{}` is not installed", factory);
+                }
+            }
+        }
+
+        let filter = gst::parse_bin_from_description(&Self::filter_description(), true)?;
+        let rgvolume = match filter.by_name("rgvolume") {
+            Some(rgvolume) => Some(Self::setup_replaygain(rgvolume, gains)?),
+            None => None,
+        };
+        let equalizer = filter.by_name("equalizer");
+        let scaletempo = filter.by_name("scaletempo").is_some();
+        playbin.set_property("audio-filter", &filter)?;
+
+        Ok((rgvolume, equalizer, scaletempo))
+    }
+
+    /// Sets `rgvolume` to the mode and pre-amp from `Config`, and to
+    /// `fallback_gain` for files without ReplayGain tags.
+    fn configure_replaygain(rgvolume: &gst::Element, fallback_gain: f64) -> Result<()> {
+        let config = config::get();
+        rgvolume.set_property("album-mode", config.replaygain == ReplayGain::Album)?;
+        rgvolume.set_property("pre-amp", config.preamp)?;
+        rgvolume.set_property("fallback-gain", fallback_gain)?;
+        Ok(())
+    }
+
+    /// Sets up `rgvolume` with the mode, pre-amp and fallback gain from
+    /// `Config`. `rgvolume` lowers the gain when the peak tags show it
+    /// would clip, and `rglimiter` after it limits what is left, e.g.
+    /// for files without peak tags. When a song starts, its fallback
+    /// gain is taken from the front of `gains`.
+    fn setup_replaygain(
+        rgvolume: gst::Element,
+        gains: &Arc<Mutex<VecDeque<f64>>>,
+    ) -> Result<gst::Element> {
+        Self::configure_replaygain(&rgvolume, config::get().fallback_gain)?;
+
+        // rgvolume reads the tags after the stream start, so the gain
+        // is in place by the time they arrive or turn out to be missing
+        let sink = rgvolume
+            .static_pad("sink")
+            .ok_or_else(|| anyhow::anyhow!("rgvolume has no sink pad"))?;
+        sink.add_probe(
+            gst::PadProbeType::EVENT_DOWNSTREAM,
+            glib::clone!(@weak rgvolume, @strong gains => @default-return gst::PadProbeReturn::Ok, move |_pad, info| {
+                if let Some(gst::PadProbeData::Event(event)) = &info.data {
+                    if let gst::EventView::StreamStart(_) = event.view() {
+                        if let Some(gain) = gains.lockk().pop_front() {
+                            rgvolume.set_property("fallback-gain", gain).ok();
+                        }
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            }),
+        );
+
+        Ok(rgvolume)
+    }
+
+    /// Sets the bands of the equalizer element to the gains in `eq`.
+    fn apply_equalizer(&self, eq: &Equalizer) -> Result<()> {
+        match &self.equalizer {
+            Some(equalizer) => Self::set_bands(equalizer, eq),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the bands of `equalizer` to the gains in `eq`.
+    fn set_bands(equalizer: &gst::Element, eq: &Equalizer) -> Result<()> {
+        for (i, gain) in eq.gains().iter().enumerate() {
+            equalizer.set_property(format!("band{}", i).as_str(), gain)?;
+        }
+        Ok(())
+    }
+
+    /// Switches the equalizer to the next preset and stores the choice
+    /// for the next run.
+    pub fn next_preset(&self) -> Result<()> {
+        let mut eq = self.eq.lockk();
+        eq.next_preset();
+        self.apply_equalizer(&eq)?;
+
+        if let Err(e) = eq.save() {
+            glib::g_warning!("backend", "Unable to save equalizer preset: {:#}", e);
+        }
+        Ok(())
+    }
+
+    /// Raises or lowers the gain of equalizer `band` by `step` dB for
+    /// the rest of the session.
+    pub fn adjust_band(&self, band: usize, step: f64) -> Result<()> {
+        let mut eq = self.eq.lockk();
+        eq.adjust(band, step);
+        self.apply_equalizer(&eq)
+    }
+
+    /// Returns the ReplayGain adjustment applied to the current song
+    /// in dB, after lowering it to prevent clipping.
+    pub fn replaygain(&self) -> Option<f64> {
+        let rgvolume = self.rgvolume.as_ref()?;
+        rgvolume.property("result-gain").ok()?.get().ok()
+    }
+
     /// Returns true if the stream is not currently paused
     pub fn playing(&self) -> bool {
         self.playbin.current_state() != gst::State::Paused
@@ -106,29 +486,254 @@ impl Backend {
         }
     }
 
-    /// Starts playback of `song`. If `song` is None, does nothing.
+    /// Returns the volume in percent, on a cubic scale so that equal
+    /// steps sound like equal changes in loudness.
+    pub fn volume(&self) -> u8 {
+        let volume = self.stream_volume().volume(StreamVolumeFormat::Cubic);
+        (volume * 100.0).round().clamp(0.0, 100.0) as u8
+    }
+
+    /// Sets the volume to `percent`, at most 100, on a cubic scale.
+    fn set_volume(&self, percent: u8) {
+        let volume = f64::from(percent.min(100)) / 100.0;
+        self.stream_volume()
+            .set_volume(StreamVolumeFormat::Cubic, volume);
+    }
+
+    /// Raises or lowers the volume by `step` percentage points and
+    /// stores the new level for the next run. While the sleep timer is
+    /// fading playback out, the level the fade started from is changed
+    /// instead, and the fade goes on from there.
+    pub fn change_volume(&self, step: i8) -> Result<()> {
+        let change = |volume: u8| (i16::from(volume) + i16::from(step)).clamp(0, 100) as u8;
+        let mut sleep = self.sleep.lockk();
+        let volume = match sleep.as_mut().and_then(|timer| timer.volume.as_mut()) {
+            Some(level) => {
+                *level = change(*level);
+                let volume = *level;
+                drop(sleep);
+                self.check_sleep();
+                volume
+            }
+            None => {
+                drop(sleep);
+                let volume = change(self.volume());
+                self.set_volume(volume);
+                volume
+            }
+        };
+
+        if let Err(e) = Self::save_volume(volume) {
+            glib::g_warning!("backend", "Unable to save volume: {:#}", e);
+        }
+        Ok(())
+    }
+
+    /// Returns `playbin` as the `StreamVolume` interface it implements.
+    fn stream_volume(&self) -> &gst_audio::StreamVolume {
+        self.playbin
+            .dynamic_cast_ref::<gst_audio::StreamVolume>()
+            .expect("playbin does not implement StreamVolume")
+    }
+
+    /// Returns the path of the file the volume is kept in between runs.
+    fn volume_file() -> PathBuf {
+        glib::user_data_dir().join("soi").join("volume")
+    }
+
+    /// Returns the volume playback was left at, if it has been changed.
+    fn saved_volume() -> Option<u8> {
+        let text = std::fs::read_to_string(Self::volume_file()).ok()?;
+        text.trim().parse().ok().filter(|v| *v <= 100)
+    }
+
+    /// Stores `volume` for the next run.
+    fn save_volume(volume: u8) -> Result<()> {
+        let file = Self::volume_file();
+        let dir = file.parent().context("Volume file has no directory")?;
+        std::fs::create_dir_all(dir).with_context(|| format!("{:?}", dir))?;
+        std::fs::write(&file, format!("{}\n", volume)).with_context(|| format!("{:?}", file))
+    }
+
+    /// Returns the gain for `song` if it has no ReplayGain tags: the
+    /// one measured by `loudness::analyze()`, or `Config::fallback_gain`
+    /// if it has not been analyzed yet.
+    fn fallback_gain(&self, song: &Song) -> f64 {
+        let config = config::get();
+        match self.loudness.lockk().get(song.content_hash.as_deref()) {
+            Some(loudness) => loudness.gain(config.replaygain),
+            None => config.fallback_gain,
+        }
+    }
+
+    /// Returns `song` as it is handed to playbin.
+    fn track(&self, song: &Song) -> Track {
+        Track {
+            path: song.path.to_path_buf(),
+            uri: song.path.to_uri(),
+            gain: self.fallback_gain(song),
+            album: song.album_info.to_string(),
+        }
+    }
+
+    /// Starts playback of `song`, cutting off any song fading out. If
+    /// `song` is None, does nothing.
     pub fn play(&self, song: Option<&Song>) -> Result<()> {
         if let Some(song) = song {
-            self.playbin.set_state(gst::State::Ready)?;
-            self.playbin.set_property("uri", song.path.to_uri())?;
-            self.playbin.set_state(gst::State::Playing)?;
-            self.main_tx
-                .send(BackendMessage::RequestNextSong)
-                .expect("Unable to send message to main()");
+            if let Some(crossfader) = &self.crossfader {
+                crossfader.lockk().cancel();
+            }
+            self.start(self.track(song))?;
         }
 
         Ok(())
     }
 
-    /// Stops playback to quit program.
+    /// Starts playback of `track` from the beginning.
+    fn start(&self, track: Track) -> Result<()> {
+        self.playbin.set_state(gst::State::Ready)?;
+        let mut gains = self.gains.lockk();
+        gains.clear();
+        gains.push_back(track.gain);
+        drop(gains);
+        self.playbin.set_property("uri", &track.uri)?;
+        *self.current.lockk() = Some(track);
+        *self.switching.lockk() = false;
+        *self.seeking.lockk() = Seeking::default();
+        *self.ab_loop.lockk() = None;
+        self.playbin.set_state(gst::State::Playing)?;
+        self.main_tx
+            .send(BackendMessage::RequestNextSong)
+            .expect("Unable to send message to main()");
+        Ok(())
+    }
+
+    /// Stops playback to quit program. A WAV file being written is
+    /// finished first, as its header is only completed at the end of
+    /// the stream.
     pub fn stop(&self) -> Result<()> {
+        if config::get().sink == Some(sink::Sink::File) {
+            // The bus watches finish stopping once the end of the stream
+            // has gone through, see `reached_end()`, or after a while if
+            // it never does
+            *self.stopping.lockk() = true;
+            match &self.crossfader {
+                Some(crossfader) => {
+                    self.playbin.set_state(gst::State::Null)?;
+                    crossfader.lockk().finish();
+                }
+                None => {
+                    self.playbin.set_state(gst::State::Playing)?;
+                    self.playbin.send_event(gst::event::Eos::new());
+                }
+            }
+            glib::source::timeout_add(
+                Duration::from_secs(5),
+                glib::clone!(@strong self as this => move || {
+                    this.finish_stopping();
+                    glib::Continue(false)
+                }),
+            );
+            return Ok(());
+        }
+        self.shut_down()
+    }
+
+    /// Completes `stop()` after the end of the stream has been written
+    /// to the WAV file. Does nothing if it has already been completed.
+    fn finish_stopping(&self) {
+        if std::mem::take(&mut *self.stopping.lockk()) {
+            if let Err(e) = self.shut_down() {
+                glib::g_warning!("backend", "Unable to stop playback: {:#}", e);
+            }
+        }
+    }
+
+    /// Stops the pipelines and tells main() to quit.
+    fn shut_down(&self) -> Result<()> {
         self.playbin.set_state(gst::State::Null)?;
+        if let Some(crossfader) = &self.crossfader {
+            crossfader.lockk().stop();
+        }
         self.main_tx
             .send(BackendMessage::ReachedEndOfPlaylist)
             .expect("Unable to send message to main()");
         Ok(())
     }
 
+    /// Switches the sleep timer to the next of `SLEEP_TIMES`, starting
+    /// it over, or off after the longest.
+    pub fn cycle_sleep_timer(&self) -> Result<()> {
+        let mut sleep = self.sleep.lockk();
+        // Undo any fade out
+        if let Some(volume) = sleep.and_then(|timer| timer.volume) {
+            self.set_volume(volume);
+        }
+
+        let length = match *sleep {
+            Some(timer) => SLEEP_TIMES.iter().find(|t| **t > timer.length).copied(),
+            None => Some(SLEEP_TIMES[0]),
+        };
+        *sleep = length.map(SleepTimer::new);
+        Ok(())
+    }
+
+    /// Fades playback out during the last `SLEEP_FADE` of the sleep
+    /// timer, and stops it when the time is up.
+    fn check_sleep(&self) {
+        let mut sleep = self.sleep.lockk();
+        let timer = match sleep.as_mut() {
+            Some(timer) => timer,
+            None => return,
+        };
+        let remaining = timer.deadline.saturating_duration_since(Instant::now());
+        if remaining > SLEEP_FADE {
+            return;
+        }
+
+        if remaining.is_zero() {
+            *sleep = None;
+            drop(sleep);
+            if let Err(e) = self.stop() {
+                glib::g_warning!("backend", "Unable to stop playback: {:#}", e);
+            }
+            return;
+        }
+
+        let volume = *timer.volume.get_or_insert_with(|| self.volume());
+        let fraction = remaining.as_secs_f64() / SLEEP_FADE.as_secs_f64();
+        self.set_volume((f64::from(volume) * fraction).round() as u8);
+    }
+
+    /// Stops playback after the current song or album, or goes on past
+    /// it if playback was already to stop there.
+    pub fn toggle_stop_after(&self, stop: StopAfter) -> Result<()> {
+        {
+            let mut stop_after = self.stop_after.lockk();
+            *stop_after = match *stop_after == Some(stop) {
+                true => None,
+                false => Some(stop),
+            };
+        }
+        // The next song is queued again with the stop taken into account
+        self.main_tx
+            .send(BackendMessage::RequestNextSong)
+            .expect("Unable to send message to main()");
+        Ok(())
+    }
+
+    /// Returns true if playback is to stop before going on to `next`.
+    pub fn stops_before(&self, next: &Song) -> bool {
+        match *self.stop_after.lockk() {
+            Some(StopAfter::Song) => true,
+            Some(StopAfter::Album) => match &*self.current.lockk() {
+                Some(current) => current.album != next.album_info,
+                None => true,
+            },
+            None => false,
+        }
+    }
+
     /// Mutes/unmutes playback
     pub fn toggle_mute(&self) -> Result<()> {
         let muted: bool = self.playbin.property("mute")?.get()?;
@@ -138,10 +743,14 @@ impl Backend {
 
     /// Pauses/unpauses playback
     pub fn toggle_pause(&self) -> Result<()> {
-        match self.playbin.current_state() {
-            gst::State::Playing => self.playbin.set_state(gst::State::Paused),
-            _ => self.playbin.set_state(gst::State::Playing),
+        let pause = self.playbin.current_state() == gst::State::Playing;
+        match pause {
+            true => self.playbin.set_state(gst::State::Paused),
+            false => self.playbin.set_state(gst::State::Playing),
         }?;
+        if let Some(crossfader) = &self.crossfader {
+            crossfader.lockk().set_paused(pause);
+        }
         Ok(())
     }
 
@@ -159,23 +768,41 @@ impl Backend {
             position: self.position(),
             playing: self.playing(),
             muted: self.muted(),
+            volume: self.volume(),
+            rate: *self.rate.lockk(),
+            replaygain: self.replaygain(),
+            equalizer: self.equalizer.as_ref().map(|_| self.eq.lockk().settings()),
+            ab_loop: *self.ab_loop.lockk(),
+            sleep: self
+                .sleep
+                .lockk()
+                .map(|timer| timer.deadline.saturating_duration_since(Instant::now())),
+            stop_after: *self.stop_after.lockk(),
         }
     }
 
     /// Sets the song to be played after the end of the current one
     /// is reached. This is necessary for gapless playback.
     pub fn enqueue(&mut self, song: Option<&Song>) {
-        *self.next_uri.lockk() = song.map(|s| s.path.to_uri());
+        *self.next.lockk() = song.map(|s| self.track(s));
     }
 
-    /// Sets the playbin URI to `self.next_uri`, when it is not None.
+    /// Sets the playbin URI to that of `self.next`, when it is not None
+    /// and is not to be crossfaded to, see `check_crossfade()`.
     /// This function is to be used from GStreamer playbin's
     /// about-to-finish callback only.
     pub fn dequeue(&self) {
-        if let Some(uri) = &*self.next_uri.lockk() {
+        if let Some(next) = &*self.next.lockk() {
+            if self.crossfades_to(next) {
+                return;
+            }
+            self.gains.lockk().push_back(next.gain);
             self.playbin
-                .set_property("uri", uri)
+                .set_property("uri", &next.uri)
                 .expect("Unable to set playbin URI");
+            *self.current.lockk() = Some(next.clone());
+            *self.switching.lockk() = true;
+            *self.ab_loop.lockk() = None;
             self.main_tx
                 .send(BackendMessage::ReachedEndOfSong)
                 .expect("Unable to send message to main()");
@@ -185,28 +812,447 @@ impl Backend {
         }
     }
 
-    /// Skips forward 5 seconds
-    pub fn seek_forward(&self) -> Result<()> {
-        if let Some(t) = self.playbin.query_position::<gst::ClockTime>() {
-            self.seek_to(t + gst::ClockTime::from_seconds(5));
+    /// Handles the end of the stream: normally the end of the playlist,
+    /// but with crossfading on, the current song may also have ended
+    /// before its crossfade could start, e.g. when it was shorter than
+    /// the time it took to start.
+    fn reached_end(&self) {
+        if *self.stopping.lockk() {
+            self.finish_stopping();
+            return;
+        }
+
+        let next = self.next.lockk().clone();
+        match next {
+            Some(next) if self.crossfades_to(&next) => self.switch_to(next),
+            _ => self
+                .main_tx
+                .send(BackendMessage::ReachedEndOfPlaylist)
+                .expect("Unable to send message to main()"),
+        }
+    }
+
+    /// Tells main() that the current song cannot be played. Several
+    /// elements may fail on the same file, but only the first error is
+    /// reported, until the next song is started.
+    fn report_error(&self, error: String) {
+        glib::g_debug!("song", "{}", error);
+        if let Some(track) = self.current.lockk().take() {
+            self.main_tx
+                .send(BackendMessage::Error(track.path, error))
+                .expect("Unable to send message to main()");
+        }
+    }
+
+    /// Returns true if the change from the current song to `next` is
+    /// to be crossfaded. In the album-aware mode, songs on the same
+    /// album follow each other gaplessly.
+    fn crossfades_to(&self, next: &Track) -> bool {
+        if self.crossfader.is_none() {
+            return false;
         }
+        match &*self.current.lockk() {
+            Some(current) => !config::get().crossfade_albums || current.album != next.album,
+            None => true,
+        }
+    }
 
+    /// Starts crossfading to the next song once the current one is
+    /// about to end, after having the current song prerolled for the
+    /// crossfader shortly before. Called from the timer updating
+    /// `main()`, so it never waits for the crossfader.
+    fn check_crossfade(&self) {
+        let crossfader = match &self.crossfader {
+            Some(crossfader) => crossfader,
+            None => return,
+        };
+        if crossfader.lockk().fading()
+            || *self.switching.lockk()
+            || self.looping()
+            || self.playbin.current_state() != gst::State::Playing
+        {
+            return;
+        }
+
+        let next = match &*self.next.lockk() {
+            Some(next) if self.crossfades_to(next) => next.clone(),
+            _ => return,
+        };
+        let current = match &*self.current.lockk() {
+            Some(current) => current.clone(),
+            None => return,
+        };
+        let (position, duration) = match (
+            self.playbin.query_position::<gst::ClockTime>(),
+            self.playbin.query_duration::<gst::ClockTime>(),
+        ) {
+            (Some(position), Some(duration)) => (position, duration),
+            _ => return,
+        };
+
+        // Short songs are faded over half of their length at most
+        let length = std::cmp::min(crossfader.lockk().duration(), Duration::from(duration) / 2);
+        let remaining = Duration::from(duration.saturating_sub(position));
+        if remaining > length + CROSSFADE_PREROLL {
+            return;
+        }
+
+        let mut crossfader = crossfader.lockk();
+        if !crossfader.prepared(&current.uri) {
+            let prepared =
+                crossfader.prepare(&current.uri, |tail| self.configure_tail(tail, current.gain));
+            if let Err(e) = prepared {
+                glib::g_warning!("backend", "Unable to crossfade: {:#}", e);
+            }
+        }
+        if remaining > length {
+            return;
+        }
+
+        // The fade is shortened by however late the song was prerolled.
+        // If it cannot be, the next song is switched to without a fade.
+        match crossfader.ready() {
+            Ok(false) if crossfader.prepared(&current.uri) => return,
+            Ok(_) => (),
+            Err(e) => {
+                glib::g_warning!("backend", "Unable to crossfade: {:#}", e);
+                crossfader.cancel();
+            }
+        }
+        drop(crossfader);
+        self.crossfade(next, remaining);
+    }
+
+    /// Fades out the current song from where playbin is now over
+    /// `length` while playbin starts playing `next`. If the current song
+    /// has not been prepared by the crossfader, playback switches to
+    /// `next` without a fade.
+    fn crossfade(&self, next: Track, length: Duration) {
+        if let Some(crossfader) = &self.crossfader {
+            let position = self.playbin.query_position::<gst::ClockTime>();
+            let volume = match self.muted() {
+                true => 0.0,
+                false => self.stream_volume().volume(StreamVolumeFormat::Linear),
+            };
+            let mut fader = crossfader.lockk();
+            if let (Ok(true), Some(position)) = (fader.ready(), position) {
+                match fader.start(position, length, *self.rate.lockk(), volume) {
+                    // The volumes are updated more often than the state
+                    // of playback, for a smooth fade
+                    Ok(()) => {
+                        glib::source::timeout_add(
+                            Duration::from_millis(20),
+                            glib::clone!(@strong crossfader => move || {
+                                glib::Continue(crossfader.lockk().update())
+                            }),
+                        );
+                    }
+                    Err(e) => {
+                        glib::g_warning!("backend", "Unable to crossfade: {:#}", e);
+                        fader.cancel();
+                    }
+                }
+            }
+        }
+
+        self.switch_to(next);
+    }
+
+    /// Sets up the audio filter of `tail`, the pipeline playing a song
+    /// fading out, like that of playbin.
+    fn configure_tail(&self, tail: &gst::Bin, gain: f64) -> Result<()> {
+        if let Some(rgvolume) = tail.by_name("rgvolume") {
+            Self::configure_replaygain(&rgvolume, gain)?;
+        }
+        if let Some(equalizer) = tail.by_name("equalizer") {
+            Self::set_bands(&equalizer, &self.eq.lockk())?;
+        }
         Ok(())
     }
 
-    /// Skips backward 5 seconds
-    pub fn seek_backward(&self) -> Result<()> {
-        if let Some(t) = self.playbin.query_position::<gst::ClockTime>() {
-            let pos = t.saturating_sub(gst::ClockTime::from_seconds(5));
-            self.seek_to(pos);
+    /// Switches playbin to `next` right away, as the song after the
+    /// current one in the playlist.
+    fn switch_to(&self, next: Track) {
+        *self.next.lockk() = None;
+        self.main_tx
+            .send(BackendMessage::ReachedEndOfSong)
+            .expect("Unable to send message to main()");
+        if let Err(e) = self.start(next) {
+            glib::g_warning!("backend", "Unable to play the next song: {:#}", e);
         }
+    }
+
+    /// Skips forward in `song`, the song being played, by `step`, or
+    /// backward if `forward` is false. Holding the key down makes the
+    /// steps grow, see `Acceleration`.
+    pub fn seek_by(&self, song: Option<&Song>, step: Duration, forward: bool) -> Result<()> {
+        let step = self
+            .acceleration
+            .lockk()
+            .step(step, forward, Instant::now());
+        let seek = match forward {
+            true => Seek::Forward(step),
+            false => Seek::Backward(step),
+        };
+        self.seek(song, seek)
+    }
+
+    /// Jumps within `song`, the song being played, never past its end.
+    pub fn seek(&self, song: Option<&Song>, seek: Seek) -> Result<()> {
+        // Some files only have their duration known once played
+        let duration = match (song, self.playbin.query_duration::<gst::ClockTime>()) {
+            (Some(song), _) if song.duration > Duration::ZERO => song.duration,
+            (Some(_), Some(duration)) => Duration::from(duration),
+            _ => return Ok(()),
+        };
 
+        let target = seek.target(self.seek_position(), duration);
+        self.seek_to(gst::ClockTime::from_nseconds(target.as_nanos() as u64));
         Ok(())
     }
 
-    /// Seeks to the specified position in the current song
+    /// Skips to the start of the next chapter of `song`, the song being
+    /// played. Does nothing if there are no more chapters.
+    pub fn next_chapter(&self, song: Option<&Song>) -> Result<()> {
+        let position = self.position();
+        let next = song
+            .into_iter()
+            .flat_map(|s| s.chapters.iter())
+            .find(|c| c.start > position);
+
+        if let Some(chapter) = next {
+            self.seek_to(gst::ClockTime::from_nseconds(
+                chapter.start.as_nanos() as u64
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Skips to the start of the current chapter of `song`, the song
+    /// being played, or to the previous chapter if the current one
+    /// started less than three seconds ago.
+    pub fn prev_chapter(&self, song: Option<&Song>) -> Result<()> {
+        let song = match song {
+            Some(song) => song,
+            None => return Ok(()),
+        };
+
+        let position = self.position();
+        let target = match song.current_chapter(position) {
+            Some(i) if position - song.chapters[i].start < Duration::from_secs(3) => {
+                i.checked_sub(1).map(|i| song.chapters[i].start)
+            }
+            Some(i) => Some(song.chapters[i].start),
+            None => None,
+        };
+
+        if let Some(start) = target {
+            self.seek_to(gst::ClockTime::from_nseconds(start.as_nanos() as u64));
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if the playback rate can be changed, see
+    /// `change_rate()`.
+    pub fn can_change_rate(&self) -> bool {
+        self.scaletempo
+    }
+
+    /// Makes playback `step` faster, or slower if negative, keeping
+    /// the pitch with `scaletempo`. A step of 0.0 returns to normal speed.
+    /// Does nothing if `scaletempo` is not installed.
+    pub fn change_rate(&self, step: f64) -> Result<()> {
+        if !self.scaletempo {
+            return Ok(());
+        }
+        {
+            let mut rate = self.rate.lockk();
+            *rate = match step == 0.0 {
+                true => 1.0,
+                // Rounded to avoid 1.2000000000000002x
+                false => {
+                    ((*rate + step) * 10.0)
+                        .round()
+                        .clamp(MIN_RATE * 10.0, MAX_RATE * 10.0)
+                        / 10.0
+                }
+            };
+        }
+        self.apply_rate();
+        Ok(())
+    }
+
+    /// Seeks to the current position at `self.rate`, unless the song
+    /// is already played at that rate.
+    fn apply_rate(&self) {
+        let mut query = gst::query::Segment::new(gst::Format::Time);
+        if !self.playbin.query(&mut query) {
+            return;
+        }
+        let (rate, _, _) = query.result();
+        if (rate - *self.rate.lockk()).abs() > 1e-6 {
+            if let Some(t) = self.playbin.query_position::<gst::ClockTime>() {
+                self.seek_to(t);
+            }
+        }
+    }
+
+    /// Marks point A of a loop at the current position, replacing any
+    /// loop there was.
+    pub fn mark_loop_start(&self) -> Result<()> {
+        let position = self.position();
+        self.clear_loop()?;
+        *self.ab_loop.lockk() = Some(AbLoop {
+            a: position,
+            b: None,
+        });
+        Ok(())
+    }
+
+    /// Marks point B of the loop at the current position and starts
+    /// looping between A and B. Without point A, the loop starts from
+    /// the beginning of the song.
+    pub fn mark_loop_end(&self) -> Result<()> {
+        let position = self.position();
+        let a = {
+            let mut ab_loop = self.ab_loop.lockk();
+            let a = ab_loop.map_or(Duration::ZERO, |l| l.a);
+            if position <= a {
+                return Ok(());
+            }
+            *ab_loop = Some(AbLoop {
+                a,
+                b: Some(position),
+            });
+            a
+        };
+        self.seek_to(gst::ClockTime::from_nseconds(a.as_nanos() as u64));
+        Ok(())
+    }
+
+    /// Removes the loop, letting playback continue past point B.
+    pub fn clear_loop(&self) -> Result<()> {
+        let looping = self.looping();
+        *self.ab_loop.lockk() = None;
+        if looping {
+            // Seeking again without the stop position of the loop
+            self.seek_to(gst::ClockTime::from_nseconds(
+                self.seek_position().as_nanos() as u64,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns true while looping between points A and B.
+    fn looping(&self) -> bool {
+        matches!(*self.ab_loop.lockk(), Some(AbLoop { b: Some(_), .. }))
+    }
+
+    /// Returns point B if `pos` is to be played in the A–B loop. Seeking
+    /// past point B removes the loop.
+    fn loop_end(&self, pos: gst::ClockTime) -> Option<gst::ClockTime> {
+        let mut ab_loop = self.ab_loop.lockk();
+        match *ab_loop {
+            Some(AbLoop { b: Some(b), .. }) if Duration::from(pos) < b => {
+                Some(gst::ClockTime::from_nseconds(b.as_nanos() as u64))
+            }
+            Some(AbLoop { b: Some(_), .. }) => {
+                *ab_loop = None;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Jumps back to point A when playback reaches point B. The seek
+    /// does not flush, so the loop plays without a gap.
+    fn repeat_loop(&self) {
+        let ab_loop = *self.ab_loop.lockk();
+        let (a, b) = match ab_loop {
+            Some(AbLoop { a, b: Some(b) }) => (a, b),
+            // The loop was removed after its last segment seek
+            _ => {
+                if let Some(pos) = self.playbin.query_position::<gst::ClockTime>() {
+                    self.seek_to(pos);
+                }
+                return;
+            }
+        };
+
+        let rate = *self.rate.lockk();
+        self.playbin
+            .seek(
+                rate,
+                gst::SeekFlags::SEGMENT | gst::SeekFlags::ACCURATE,
+                gst::SeekType::Set,
+                Some(gst::ClockTime::from_nseconds(a.as_nanos() as u64)),
+                gst::SeekType::Set,
+                Some(gst::ClockTime::from_nseconds(b.as_nanos() as u64)),
+            )
+            .ok(); // ignore any errors
+    }
+
+    /// Returns the position the last seek requested goes to, or the
+    /// current position if there is none, so that steps add up while
+    /// playbin is still seeking.
+    fn seek_position(&self) -> Duration {
+        let seeking = self.seeking.lockk();
+        match seeking.pending.or(seeking.target) {
+            Some(pos) => Duration::from(pos),
+            None => self.position(),
+        }
+    }
+
+    /// Seeks to the specified position in the current song, keeping
+    /// the playback rate. If playbin is still carrying out an earlier
+    /// seek, the position is seeked to once it is done, replacing any
+    /// other position requested in the meantime.
     fn seek_to(&self, pos: gst::ClockTime) {
-        self.playbin.seek_simple(gst::SeekFlags::FLUSH, pos).ok(); // ignore any errors
+        {
+            let mut seeking = self.seeking.lockk();
+            if seeking.target.is_some() {
+                seeking.pending = Some(pos);
+                return;
+            }
+            seeking.target = Some(pos);
+        }
+
+        // Within an A–B loop, playback stops at B with SegmentDone
+        let (flags, stop_type, stop) = match self.loop_end(pos) {
+            Some(b) => (
+                gst::SeekFlags::FLUSH | gst::SeekFlags::SEGMENT,
+                gst::SeekType::Set,
+                Some(b),
+            ),
+            None => (
+                gst::SeekFlags::FLUSH,
+                gst::SeekType::None,
+                gst::ClockTime::NONE,
+            ),
+        };
+        let rate = *self.rate.lockk();
+        let result = self
+            .playbin
+            .seek(rate, flags, gst::SeekType::Set, Some(pos), stop_type, stop);
+        // No AsyncDone follows a seek that failed
+        if result.is_err() {
+            *self.seeking.lockk() = Seeking::default();
+        }
+    }
+
+    /// Carries out the seek requested while playbin was seeking, once
+    /// it is done.
+    fn seek_done(&self) {
+        let pending = {
+            let mut seeking = self.seeking.lockk();
+            seeking.target = None;
+            seeking.pending.take()
+        };
+        if let Some(pos) = pending {
+            self.seek_to(pos);
+        }
     }
 }
 
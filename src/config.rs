@@ -0,0 +1,218 @@
+//! Settings given on the command line.
+
+use crate::cover::Protocol;
+use crate::pattern::{self, Pattern};
+use crate::sink::Sink;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Global configuration, set once from main() after the command line
+/// has been parsed.
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// User-adjustable settings affecting how the playlist is built
+/// and shown.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Whether leading articles ("The", "A", "An") are skipped when
+    /// sorting names that have no sort tag.
+    pub ignore_articles: bool,
+
+    /// Album artist names that mark an album as a compilation,
+    /// compared case-insensitively.
+    pub various_artists: Vec<String>,
+
+    /// Which date albums are shown with and sorted by
+    pub date: DateKind,
+
+    /// Whether to list every file that could not be played
+    pub verbose: bool,
+
+    /// How album covers are drawn, None if they are not
+    pub cover: Option<Protocol>,
+
+    /// Names of image files used as album artwork, in order of
+    /// preference and without the extension, compared case-insensitively
+    pub artwork_names: Vec<String>,
+
+    /// Only songs rated at least this many stars are played
+    pub min_rating: Option<u8>,
+
+    /// Whether only songs marked as favourites are played
+    pub favourites: bool,
+
+    /// Only songs by an artist whose name contains this are played
+    pub artist: Option<String>,
+
+    /// Only songs in a genre whose name contains this are played
+    pub genre: Option<String>,
+
+    /// Only songs by a composer whose name contains this are played
+    pub composer: Option<String>,
+
+    /// Shown between the values of tags that have several, e.g. two
+    /// artists
+    pub separator: String,
+
+    /// Patterns for reading missing tags from file names, tried in order
+    pub patterns: Vec<Pattern>,
+
+    /// Patterns for reading the album artist, album and year of untagged
+    /// files from directory names, tried in order
+    pub dir_patterns: Vec<Pattern>,
+
+    /// Which ReplayGain tags are used to even out the volume
+    pub replaygain: ReplayGain,
+
+    /// Gain added to the ReplayGain adjustment, in dB
+    pub preamp: f64,
+
+    /// Gain applied to files without ReplayGain tags that have not
+    /// been analyzed yet, in dB
+    pub fallback_gain: f64,
+
+    /// Audio output, None for the default chosen by GStreamer
+    pub sink: Option<Sink>,
+
+    /// Output device of `sink`, or the path of the WAV file written
+    /// with `Sink::File`
+    pub device: Option<String>,
+
+    /// List the audio output devices and exit instead of playing
+    pub list_devices: bool,
+
+    /// Length of the crossfade between songs, None for gapless playback
+    pub crossfade: Option<Duration>,
+
+    /// Crossfade only between albums, keeping playback within an album
+    /// gapless
+    pub crossfade_albums: bool,
+
+    /// Volume in percent to start playback with, instead of the one
+    /// playback was left at
+    pub volume: Option<u8>,
+
+    /// Time after which playback fades out and stops, None to play on
+    pub sleep: Option<Duration>,
+
+    /// How far the seek keys skip, before they accelerate
+    pub seek_step: Duration,
+
+    /// How far the long seek keys skip
+    pub long_seek_step: Duration,
+
+    /// Analyze the loudness of the given files and exit instead of
+    /// playing them
+    pub scan_loudness: bool,
+}
+
+/// ReplayGain mode, see `Backend::new()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplayGain {
+    /// Every track is played at the same loudness
+    Track,
+    /// Albums are played at the same loudness, keeping the differences
+    /// between their tracks
+    Album,
+    /// ReplayGain tags are ignored
+    Off,
+}
+
+/// Release date to use for albums that have been reissued.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DateKind {
+    /// Release date of the edition in the files
+    Release,
+    /// Date the album was first released
+    Original,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ignore_articles: false,
+            various_artists: [
+                "Various Artists",
+                "Various",
+                "VA",
+                "V.A.",
+                "Verschiedene",
+                "Verschiedene Interpreten",
+                "Divers",
+                "Artistes divers",
+                "Varios",
+                "Varios artistas",
+                "Eri esittäjiä",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+            date: DateKind::Release,
+            verbose: false,
+            cover: Some(Protocol::detect()),
+            artwork_names: ["cover", "folder", "front", "album", "albumart"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            min_rating: None,
+            favourites: false,
+            artist: None,
+            genre: None,
+            composer: None,
+            separator: ", ".to_string(),
+            patterns: pattern::DEFAULT_PATTERNS
+                .iter()
+                .map(|p| Pattern::parse(p).expect("Invalid built-in pattern"))
+                .collect(),
+            dir_patterns: pattern::DEFAULT_DIR_PATTERNS
+                .iter()
+                .map(|p| Pattern::parse(p).expect("Invalid built-in pattern"))
+                .collect(),
+            replaygain: ReplayGain::Album,
+            preamp: 0.0,
+            fallback_gain: 0.0,
+            sink: None,
+            device: None,
+            list_devices: false,
+            crossfade: None,
+            crossfade_albums: false,
+            volume: None,
+            sleep: None,
+            seek_step: Duration::from_secs(5),
+            long_seek_step: Duration::from_secs(60),
+            scan_loudness: false,
+        }
+    }
+}
+
+impl Config {
+    /// Returns true if `album_artist` is one of the configured
+    /// "Various Artists" aliases.
+    pub fn is_various_artists(&self, album_artist: &str) -> bool {
+        self.various_artists
+            .iter()
+            .any(|alias| alias.to_lowercase() == album_artist.to_lowercase())
+    }
+
+    /// Returns true if any filter, e.g. `--min-rating` or `--artist`,
+    /// has been set.
+    pub fn has_filters(&self) -> bool {
+        self.min_rating.is_some()
+            || self.favourites
+            || self.artist.is_some()
+            || self.genre.is_some()
+            || self.composer.is_some()
+    }
+}
+
+/// Returns the global configuration. If `set()` has not been called,
+/// e.g. in tests, the defaults are returned.
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Sets the global configuration. Has no effect if the configuration
+/// has already been set or read.
+pub fn set(config: Config) {
+    CONFIG.set(config).ok();
+}
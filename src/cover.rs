@@ -0,0 +1,386 @@
+//! Draws album covers in the terminal.
+
+use crate::traits::{AudioPlaybin, PathToURI};
+
+use anyhow::{anyhow, Context, Result};
+use gst::prelude::*;
+use itertools::Itertools;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use termion::color;
+
+/// Width of the cover in terminal cells
+pub const COVER_COLS: usize = 16;
+/// Height of the cover in terminal cells
+pub const COVER_ROWS: usize = 8;
+
+/// How long to wait for GStreamer to decode a cover image
+const DECODE_TIMEOUT: u64 = 5; // seconds
+
+/// Ways of drawing images in the terminal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// kitty graphics protocol
+    Kitty,
+    /// iTerm2 inline images
+    Iterm,
+    /// DEC sixel graphics
+    Sixel,
+    /// Unicode half blocks with 24-bit colors, works everywhere
+    Blocks,
+}
+
+impl Protocol {
+    /// Guesses the best protocol supported by the terminal from
+    /// the environment variables it sets.
+    pub fn detect() -> Self {
+        let var = |name| std::env::var(name).unwrap_or_default();
+        let term = var("TERM");
+
+        if std::env::var_os("KITTY_WINDOW_ID").is_some()
+            || term == "xterm-kitty"
+            || var("TERM_PROGRAM") == "ghostty"
+        {
+            Self::Kitty
+        } else if matches!(var("TERM_PROGRAM").as_str(), "iTerm.app" | "WezTerm") {
+            Self::Iterm
+        } else if term.contains("sixel") || term.starts_with("foot") || term == "mlterm" {
+            Self::Sixel
+        } else {
+            Self::Blocks
+        }
+    }
+}
+
+/// A cover image decoded for drawing with a given `Protocol`.
+pub struct Cover {
+    protocol: Protocol,
+    /// The image as it was embedded in the file, for iTerm2
+    encoded: Vec<u8>,
+    /// The image scaled to `width` x `height`, three bytes per pixel
+    rgb: Vec<u8>,
+    width: usize,
+    height: usize,
+}
+
+impl Cover {
+    /// Decodes and scales the image in `sample`, taken from the
+    /// `gst::tags::Image` tag, to the size needed by `protocol`.
+    pub fn decode(sample: &gst::Sample, protocol: Protocol) -> Result<Self> {
+        let encoded = sample
+            .buffer()
+            .context("Cover image has no data")?
+            .map_readable()?
+            .to_vec();
+
+        let (width, height) = match protocol {
+            Protocol::Blocks => (COVER_COLS, COVER_ROWS * 2),
+            _ => {
+                let (cell_width, cell_height) = cell_size();
+                (COVER_COLS * cell_width, COVER_ROWS * cell_height)
+            }
+        };
+
+        // iTerm2 decodes and scales the image itself
+        let rgb = match protocol {
+            Protocol::Iterm => Vec::new(),
+            _ => decode_rgb(sample, width, height)?,
+        };
+
+        Ok(Self {
+            protocol,
+            encoded,
+            rgb,
+            width,
+            height,
+        })
+    }
+
+    /// Reads and decodes the image embedded in the audio file at `path`.
+    pub fn from_audio_file(path: &Path, protocol: Protocol) -> Result<Self> {
+        Self::decode(&read_embedded_image(path)?, protocol)
+    }
+
+    /// Reads and decodes the image file at `path`, e.g. cover.jpg next
+    /// to the audio files.
+    pub fn from_file(path: &Path, protocol: Protocol) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("{:?}", path))?;
+        let buffer = gst::Buffer::from_slice(data);
+        let sample = gst::Sample::builder().buffer(&buffer).build();
+        Self::decode(&sample, protocol)
+    }
+
+    /// Returns the cover as lines of `COVER_COLS` half blocks, the
+    /// upper and lower half of each colored after one pixel.
+    pub fn blocks(&self) -> Vec<String> {
+        let pixel = |x: usize, y: usize| {
+            let i = (y * self.width + x) * 3;
+            color::Rgb(self.rgb[i], self.rgb[i + 1], self.rgb[i + 2])
+        };
+
+        (0..COVER_ROWS)
+            .map(|row| {
+                let mut line: String = (0..COVER_COLS)
+                    .map(|x| {
+                        format!(
+                            "{}{}▀",
+                            color::Fg(pixel(x, row * 2)),
+                            color::Bg(pixel(x, row * 2 + 1))
+                        )
+                    })
+                    .collect();
+                line.push_str(&format!(
+                    "{}{}",
+                    color::Fg(color::Reset),
+                    color::Bg(color::Reset)
+                ));
+                line
+            })
+            .collect()
+    }
+
+    /// Returns the escape sequence drawing the cover at the cursor
+    /// position. Not used with `Protocol::Blocks`, see `blocks()`.
+    pub fn escape_sequence(&self) -> String {
+        match self.protocol {
+            Protocol::Kitty => self.kitty(),
+            Protocol::Iterm => self.iterm(),
+            Protocol::Sixel => self.sixel(),
+            Protocol::Blocks => String::new(),
+        }
+    }
+
+    /// Returns the escape sequence removing any previously drawn cover,
+    /// when the protocol needs one. Other protocols draw into the
+    /// terminal cells, and the cover is erased by overwriting them.
+    pub fn clear_sequence(protocol: Protocol) -> &'static str {
+        match protocol {
+            Protocol::Kitty => "\x1b_Ga=d,d=a,q=2\x1b\\",
+            _ => "",
+        }
+    }
+
+    /// kitty: raw RGB data, sent in chunks of at most 4096 bytes
+    /// https://sw.kovidgoyal.net/kitty/graphics-protocol/
+    fn kitty(&self) -> String {
+        let data = glib::base64_encode(&self.rgb);
+        let chunks: Vec<&[u8]> = data.as_bytes().chunks(4096).collect();
+
+        let mut ret = String::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let more = if i + 1 < chunks.len() { 1 } else { 0 };
+            let header = match i {
+                0 => format!(
+                    "a=T,f=24,s={},v={},c={},r={},C=1,q=2,m={}",
+                    self.width, self.height, COVER_COLS, COVER_ROWS, more
+                ),
+                _ => format!("m={}", more),
+            };
+            ret.push_str(&format!(
+                "\x1b_G{};{}\x1b\\",
+                header,
+                String::from_utf8_lossy(chunk)
+            ));
+        }
+        ret
+    }
+
+    /// iTerm2: the original image file, scaled by the terminal
+    /// https://iterm2.com/documentation-images.html
+    fn iterm(&self) -> String {
+        format!(
+            "\x1b]1337;File=inline=1;size={};width={};height={};preserveAspectRatio=1:{}\x07",
+            self.encoded.len(),
+            COVER_COLS,
+            COVER_ROWS,
+            glib::base64_encode(&self.encoded)
+        )
+    }
+
+    /// Sixel: the image quantized to a 6x6x6 color cube, drawn in
+    /// bands of six pixel rows.
+    fn sixel(&self) -> String {
+        let level = |c: u8| (usize::from(c) * 5 + 127) / 255;
+        let palette_index: Vec<usize> = self
+            .rgb
+            .chunks_exact(3)
+            .map(|p| level(p[0]) * 36 + level(p[1]) * 6 + level(p[2]))
+            .collect();
+
+        let mut ret = format!("\x1bP0;1;0q\"1;1;{};{}", self.width, self.height);
+        for i in 0..216 {
+            let percent = |l: usize| l * 100 / 5;
+            ret.push_str(&format!(
+                "#{};2;{};{};{}",
+                i,
+                percent(i / 36),
+                percent(i / 6 % 6),
+                percent(i % 6)
+            ));
+        }
+
+        for band in (0..self.height).step_by(6) {
+            let rows = band..std::cmp::min(band + 6, self.height);
+            let mut colors: Vec<usize> = rows
+                .clone()
+                .flat_map(|y| palette_index[y * self.width..(y + 1) * self.width].iter())
+                .copied()
+                .collect();
+            colors.sort_unstable();
+            colors.dedup();
+
+            for color in colors {
+                ret.push_str(&format!("#{}", color));
+                let sixels = (0..self.width).map(|x| {
+                    let bits = rows
+                        .clone()
+                        .filter(|y| palette_index[y * self.width + x] == color)
+                        .fold(0u8, |acc, y| acc | 1 << (y - band));
+                    char::from(63 + bits)
+                });
+
+                // Run-length encode repeated sixels
+                for (n, sixel) in sixels.dedup_with_count() {
+                    match n {
+                        1..=3 => ret.extend(std::iter::repeat_n(sixel, n)),
+                        _ => ret.push_str(&format!("!{}{}", n, sixel)),
+                    }
+                }
+                ret.push('$');
+            }
+            ret.push('-');
+        }
+
+        ret.push_str("\x1b\\");
+        ret
+    }
+}
+
+/// Returns the embedded front cover in `tags`, or if there is none, any
+/// other embedded image or the preview image.
+pub fn embedded_image(tags: &gst::TagList) -> Option<gst::Sample> {
+    let front_cover = |sample: &&gst::Sample| {
+        let structure = sample.caps().and_then(|caps| caps.structure(0));
+        let image_type = structure.and_then(|s| s.value("image-type").ok());
+        image_type
+            .and_then(|v| glib::EnumValue::from_value(v))
+            .is_some_and(|v| v.nick() == "front-cover")
+    };
+
+    let images: Vec<gst::Sample> = tags
+        .iter_tag::<gst::tags::Image>()
+        .map(|v| v.get())
+        .collect();
+
+    images
+        .iter()
+        .find(front_cover)
+        .or_else(|| images.first())
+        .cloned()
+        .or_else(|| tags.get::<gst::tags::PreviewImage>().map(|v| v.get()))
+}
+
+/// Reads the tags of the audio file at `path` until it has prerolled,
+/// and returns the image embedded in them.
+fn read_embedded_image(path: &Path) -> Result<gst::Sample> {
+    let playbin = gst::ElementFactory::make("playbin", None)?;
+    playbin.set_property("audio-sink", gst::ElementFactory::make("fakesink", None)?)?;
+    playbin.disable_video()?;
+    playbin.set_property("uri", path.to_uri())?;
+    let bus = playbin.bus().context("Cover pipeline bus")?;
+    playbin.set_state(gst::State::Paused)?;
+
+    // Tags are posted before the pipeline has prerolled
+    let deadline = Instant::now() + Duration::from_secs(DECODE_TIMEOUT);
+    let mut image = None;
+    while image.is_none() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let msg = bus.timed_pop_filtered(
+            gst::ClockTime::from_nseconds(remaining.as_nanos() as u64),
+            &[
+                gst::MessageType::Tag,
+                gst::MessageType::AsyncDone,
+                gst::MessageType::Error,
+            ],
+        );
+        match msg.as_ref().map(|m| m.view()) {
+            Some(gst::MessageView::Tag(tags)) => image = embedded_image(&tags.tags()),
+            _ => break,
+        }
+    }
+
+    playbin.set_state(gst::State::Null)?;
+    image.context("No image embedded in the file")
+}
+
+/// Returns the size of a terminal cell in pixels, or a guess if the
+/// terminal does not report its size in pixels.
+fn cell_size() -> (usize, usize) {
+    let cells = termion::terminal_size();
+    let pixels = termion::terminal_size_pixels();
+
+    match (cells, pixels) {
+        (Ok((cols, rows)), Ok((width, height))) if cols > 0 && rows > 0 && width > 0 => {
+            (usize::from(width / cols), usize::from(height / rows))
+        }
+        _ => (8, 16),
+    }
+}
+
+/// Decodes the image in `sample` with GStreamer and scales it to
+/// `width` x `height` pixels of RGB.
+fn decode_rgb(sample: &gst::Sample, width: usize, height: usize) -> Result<Vec<u8>> {
+    let pipeline = gst::parse_launch(&format!(
+        "appsrc name=src ! decodebin ! videoconvert ! videoscale \
+         ! video/x-raw,format=RGB,width={},height={},pixel-aspect-ratio=1/1 \
+         ! appsink name=sink",
+        width, height
+    ))?
+    .downcast::<gst::Bin>()
+    .map_err(|_| anyhow!("Cover pipeline is not a bin"))?;
+
+    let src = pipeline.by_name("src").context("appsrc")?;
+    let sink = pipeline.by_name("sink").context("appsink")?;
+
+    // Without caps, e.g. for image files, decodebin finds the type itself
+    src.set_property("caps", sample.caps_owned())?;
+    src.emit_by_name("push-buffer", &[&sample.buffer_owned()])?;
+    src.emit_by_name("end-of-stream", &[])?;
+
+    pipeline.set_state(gst::State::Paused)?;
+    let msg = pipeline
+        .bus()
+        .context("Cover pipeline bus")?
+        .timed_pop_filtered(
+            gst::ClockTime::from_seconds(DECODE_TIMEOUT),
+            &[gst::MessageType::AsyncDone, gst::MessageType::Error],
+        );
+
+    let ret = match msg.as_ref().map(|m| m.view()) {
+        Some(gst::MessageView::AsyncDone(_)) => sink
+            .emit_by_name("pull-preroll", &[])?
+            .and_then(|v| v.get::<gst::Sample>().ok())
+            .and_then(|s| s.buffer_owned())
+            .context("No decoded cover image")
+            .and_then(|buffer| {
+                // Rows of RGB video are padded to four bytes
+                let data = buffer.map_readable()?;
+                let stride = (width * 3).div_ceil(4) * 4;
+                let rgb: Vec<u8> = data
+                    .chunks(stride)
+                    .take(height)
+                    .flat_map(|row| row.iter().take(width * 3).copied())
+                    .collect();
+
+                match rgb.len() == width * height * 3 {
+                    true => Ok(rgb),
+                    false => Err(anyhow!("Decoded cover image has the wrong size")),
+                }
+            }),
+        Some(gst::MessageView::Error(e)) => Err(anyhow!("{}", e.error())),
+        _ => Err(anyhow!("Timed out decoding cover image")),
+    };
+
+    pipeline.set_state(gst::State::Null)?;
+    ret
+}
@@ -0,0 +1,286 @@
+//! Crossfading between songs, as an alternative to gapless playback.
+//!
+//! The output of `playbin` is sent through `interaudiosink` to an output
+//! pipeline of its own, where it is mixed with the end of the previous
+//! song, decoded by a second "tail" pipeline:
+//!
+//! ```text
+//! playbin → interaudiosink ┄ interaudiosrc → volume ┐
+//!                                                    audiomixer → sink
+//! tail    → interaudiosink ┄ interaudiosrc → volume ┘
+//! ```
+//!
+//! As the output pipeline runs on its own, playbin can move on to the
+//! next song while the tail is still fading out. The tail is prerolled
+//! a few seconds before the fade, so that it can start without waiting
+//! from where playbin is at that moment.
+
+use anyhow::{anyhow, Context, Result};
+use gst::prelude::*;
+use std::time::Duration;
+
+/// Channel of the inter elements carrying the output of playbin
+const MAIN_CHANNEL: &str = "soi-main";
+/// Channel of the inter elements carrying the end of the previous song
+const TAIL_CHANNEL: &str = "soi-tail";
+
+/// Format both inputs of `audiomixer` are converted to
+const MIX_CAPS: &str = "audio/x-raw,format=F32LE,rate=48000,channels=2";
+
+/// A song fading out, decoded by a pipeline of its own.
+struct Tail {
+    pipeline: gst::Element,
+    uri: String,
+    /// Position in the song where the fade started, None until then
+    start: Option<gst::ClockTime>,
+    /// Length of the fade, at most what was left of the song
+    length: Duration,
+}
+
+/// Mixes the song being played by `playbin` with the end of the
+/// previous one while crossfading.
+pub struct Crossfader {
+    output: gst::Element,
+    /// Volume of playbin's output, faded in
+    main_fade: gst::Element,
+    /// Volume of the tail, faded out
+    tail_fade: gst::Element,
+    tail: Option<Tail>,
+    /// Elements the tail is played through, as in playbin's audio filter
+    filter: String,
+    /// Length of the fade
+    duration: Duration,
+}
+
+impl Crossfader {
+    /// Builds and starts the output pipeline, playing through `sink`
+    /// or, if it is None, the default sink chosen by GStreamer. Songs
+    /// fading out are played through the elements in `filter`. Returns
+    /// the crossfader and the element to be used as playbin's
+    /// `audio-sink`.
+    pub fn new(
+        duration: Duration,
+        sink: Option<gst::Element>,
+        filter: String,
+    ) -> Result<(Self, gst::Element)> {
+        let branch = |channel, name, volume| {
+            format!(
+                "interaudiosrc channel={} ! audioconvert ! audioresample ! {} ! \
+                 volume name={} volume={} ! mix.",
+                channel, MIX_CAPS, name, volume
+            )
+        };
+        let output = gst::parse_launch(&format!(
+            "{} {} audiomixer name=mix ! audioconvert name=out",
+            branch(MAIN_CHANNEL, "main-fade", 1.0),
+            branch(TAIL_CHANNEL, "tail-fade", 0.0),
+        ))?;
+        let bin = output
+            .downcast_ref::<gst::Bin>()
+            .context("Output pipeline is not a bin")?;
+
+        let sink = match sink {
+            Some(sink) => sink,
+            None => gst::ElementFactory::make("autoaudiosink", None)?,
+        };
+        bin.add(&sink)?;
+        bin.by_name("out").context("out")?.link(&sink)?;
+
+        let main_fade = bin.by_name("main-fade").context("main-fade")?;
+        let tail_fade = bin.by_name("tail-fade").context("tail-fade")?;
+        output.set_state(gst::State::Playing)?;
+
+        let playbin_sink = gst::ElementFactory::make("interaudiosink", None)
+            .context("Unable to create the `interaudiosink` element")?;
+        playbin_sink.set_property("channel", MAIN_CHANNEL)?;
+
+        let crossfader = Self {
+            output,
+            main_fade,
+            tail_fade,
+            tail: None,
+            filter,
+            duration,
+        };
+        Ok((crossfader, playbin_sink))
+    }
+
+    /// Returns the length of a crossfade.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Returns true while a song is fading out.
+    pub fn fading(&self) -> bool {
+        matches!(&self.tail, Some(tail) if tail.start.is_some())
+    }
+
+    /// Starts decoding the song at `uri` in the background, to be
+    /// faded out by `start()` once it has been prerolled. `configure` is
+    /// called with the new pipeline to set up the filter. Any song
+    /// fading out is cut off.
+    pub fn prepare<F>(&mut self, uri: &str, configure: F) -> Result<()>
+    where
+        F: FnOnce(&gst::Bin) -> Result<()>,
+    {
+        self.cancel();
+
+        let pipeline = gst::parse_launch(&format!(
+            "uridecodebin name=src ! {} ! volume name=volume ! interaudiosink channel={}",
+            self.filter, TAIL_CHANNEL
+        ))?;
+        let bin = pipeline
+            .clone()
+            .downcast::<gst::Bin>()
+            .map_err(|_| anyhow!("Tail pipeline is not a bin"))?;
+        bin.by_name("src")
+            .context("uridecodebin")?
+            .set_property("uri", uri)?;
+
+        // The tail is kept even if it fails to start, for `ready()` to
+        // report the error instead of the song being prepared again
+        self.tail = Some(Tail {
+            pipeline: pipeline.clone(),
+            uri: uri.to_string(),
+            start: None,
+            length: Duration::ZERO,
+        });
+        configure(&bin)?;
+        // Prerolling takes a while, the song can only be seeked after it
+        pipeline.set_state(gst::State::Paused)?;
+        Ok(())
+    }
+
+    /// Returns true if the song at `uri` has been passed to `prepare()`
+    /// and is not fading out yet.
+    pub fn prepared(&self, uri: &str) -> bool {
+        matches!(&self.tail, Some(tail) if tail.uri == uri && tail.start.is_none())
+    }
+
+    /// Returns true once the prepared song can be started right away,
+    /// or an error if it cannot be decoded.
+    pub fn ready(&self) -> Result<bool> {
+        match &self.tail {
+            Some(tail) if tail.start.is_none() => match tail.pipeline.state(gst::ClockTime::ZERO) {
+                (result, gst::State::Null, gst::State::VoidPending) => {
+                    result?;
+                    Err(anyhow!("Song fading out was not started"))
+                }
+                (result, state, _) => {
+                    Ok(result? == gst::StateChangeSuccess::Success && state == gst::State::Paused)
+                }
+            },
+            _ => Ok(false),
+        }
+    }
+
+    /// Plays the prepared song from `position`, at `rate` and `volume`,
+    /// and fades it out over `length`. The output of playbin is faded in
+    /// from silence, so playbin is to switch to the next song right
+    /// after this.
+    pub fn start(
+        &mut self,
+        position: gst::ClockTime,
+        length: Duration,
+        rate: f64,
+        volume: f64,
+    ) -> Result<()> {
+        let tail = self.tail.as_mut().context("No song was prepared")?;
+        let bin = tail
+            .pipeline
+            .downcast_ref::<gst::Bin>()
+            .context("Tail pipeline is not a bin")?;
+        bin.by_name("volume")
+            .context("volume")?
+            .set_property("volume", volume)?;
+        tail.pipeline.seek(
+            rate,
+            gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE,
+            gst::SeekType::Set,
+            Some(position),
+            gst::SeekType::None,
+            gst::ClockTime::NONE,
+        )?;
+        tail.pipeline.set_state(gst::State::Playing)?;
+        tail.start = Some(position);
+        tail.length = length;
+
+        self.tail_fade.set_property("volume", 1.0)?;
+        self.main_fade.set_property("volume", 0.0)?;
+        Ok(())
+    }
+
+    /// Updates the volumes of the songs fading in and out, keeping the
+    /// total power constant. The fade follows the position of the tail,
+    /// so it stops while playback is paused. Returns false once the
+    /// fade is over or the song has ended.
+    pub fn update(&mut self) -> bool {
+        let (tail, start) = match &self.tail {
+            Some(tail) => match tail.start {
+                Some(start) => (tail, start),
+                None => return false,
+            },
+            None => return false,
+        };
+
+        let position = tail.pipeline.query_position::<gst::ClockTime>();
+        let elapsed = position.map_or(Duration::ZERO, |p| Duration::from(p.saturating_sub(start)));
+        let progress = elapsed.as_secs_f64() / tail.length.as_secs_f64().max(0.001);
+        let ended = tail
+            .pipeline
+            .bus()
+            .and_then(|bus| bus.pop_filtered(&[gst::MessageType::Eos, gst::MessageType::Error]))
+            .is_some();
+
+        if progress >= 1.0 || ended {
+            self.cancel();
+            return false;
+        }
+
+        let angle = progress * std::f64::consts::FRAC_PI_2;
+        self.main_fade.set_property("volume", angle.sin()).ok();
+        self.tail_fade.set_property("volume", angle.cos()).ok();
+        true
+    }
+
+    /// Pauses or resumes the song fading out along with playbin.
+    pub fn set_paused(&self, paused: bool) {
+        if let Some(tail) = self.tail.as_ref().filter(|t| t.start.is_some()) {
+            let state = match paused {
+                true => gst::State::Paused,
+                false => gst::State::Playing,
+            };
+            tail.pipeline.set_state(state).ok();
+        }
+    }
+
+    /// Stops any song fading out or prepared and plays playbin's output
+    /// at full volume, e.g. when the user skips to another song.
+    pub fn cancel(&mut self) {
+        if let Some(tail) = self.tail.take() {
+            tail.pipeline.set_state(gst::State::Null).ok();
+        }
+        self.tail_fade.set_property("volume", 0.0).ok();
+        self.main_fade.set_property("volume", 1.0).ok();
+    }
+
+    /// Returns the bus of the output pipeline, on which the end of the
+    /// stream sent by `finish()` arrives.
+    pub fn bus(&self) -> Option<gst::Bus> {
+        self.output.bus()
+    }
+
+    /// Sends the end of the stream through the output pipeline, so that
+    /// a WAV file being written gets its header completed. The pipeline
+    /// is to be stopped once it arrives on `bus()`.
+    pub fn finish(&mut self) {
+        self.cancel();
+        self.output.send_event(gst::event::Eos::new());
+    }
+
+    /// Stops the output pipeline to quit the program.
+    pub fn stop(&mut self) {
+        self.cancel();
+        self.output.set_state(gst::State::Null).ok();
+    }
+}
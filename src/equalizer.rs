@@ -0,0 +1,236 @@
+//! Equalizer presets and the gains of the bands being played with.
+
+use anyhow::{anyhow, Context, Result};
+use std::convert::TryInto;
+use std::path::PathBuf;
+
+/// Number of bands of GStreamer's `equalizer-10bands`
+pub const BANDS: usize = 10;
+
+/// Center frequencies of the bands, as shown to the user
+pub const FREQUENCIES: [&str; BANDS] = [
+    "29 Hz", "59 Hz", "119 Hz", "237 Hz", "474 Hz", "947 Hz", "1.9 kHz", "3.8 kHz", "7.5 kHz",
+    "15 kHz",
+];
+
+/// Lowest gain of a band in dB, as allowed by `equalizer-10bands`
+pub const MIN_GAIN: f64 = -24.0;
+/// Highest gain of a band in dB
+pub const MAX_GAIN: f64 = 12.0;
+
+/// Presets available without a config file, from the lowest band
+/// to the highest
+const BUILTIN_PRESETS: [(&str, [f64; BANDS]); 9] = [
+    ("flat", [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+    ("rock", [5.0, 4.0, 3.0, 1.0, -1.0, -1.0, 1.0, 3.0, 4.0, 5.0]),
+    (
+        "pop",
+        [-1.0, 1.0, 3.0, 4.0, 3.0, 1.0, -1.0, -1.0, -1.0, -1.0],
+    ),
+    ("jazz", [3.0, 2.0, 1.0, 2.0, -1.0, -1.0, 0.0, 1.0, 2.0, 3.0]),
+    (
+        "classical",
+        [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, -4.0, -4.0, -4.0, -6.0],
+    ),
+    (
+        "loudness",
+        [6.0, 4.0, 2.0, 0.0, -1.0, 0.0, 0.0, 2.0, 4.0, 5.0],
+    ),
+    ("bass", [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+    ("treble", [0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 4.0, 5.0, 6.0]),
+    (
+        "vocal",
+        [-2.0, -2.0, -1.0, 1.0, 3.0, 3.0, 2.0, 1.0, 0.0, -1.0],
+    ),
+];
+
+/// A named set of band gains in dB.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preset {
+    pub name: String,
+    pub gains: [f64; BANDS],
+}
+
+/// What `Output` needs to show the equalizer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub preset: String,
+    /// True if the bands have been adjusted after choosing the preset
+    pub modified: bool,
+    pub gains: [f64; BANDS],
+}
+
+/// The presets to choose from and the gains currently applied. The
+/// gains start from the active preset and can be adjusted band by band
+/// for the rest of the session; only the choice of preset is stored.
+#[derive(Clone, Debug)]
+pub struct Equalizer {
+    presets: Vec<Preset>,
+    /// Index of the active preset in `presets`
+    current: usize,
+    gains: [f64; BANDS],
+}
+
+impl Equalizer {
+    /// Reads the built-in presets, those defined by the user in
+    /// `$XDG_CONFIG_HOME/soi/equalizer`, and the preset chosen last time.
+    pub fn load() -> Self {
+        let file = glib::user_config_dir().join("soi").join("equalizer");
+        let text = std::fs::read_to_string(&file).unwrap_or_default();
+        let active = std::fs::read_to_string(Self::preset_file()).unwrap_or_default();
+        Self::new(&text, active.trim())
+    }
+
+    /// Returns the built-in presets followed by the ones in `text`,
+    /// with `active` chosen if it is one of them, otherwise "flat".
+    fn new(text: &str, active: &str) -> Self {
+        let mut presets: Vec<Preset> = BUILTIN_PRESETS
+            .iter()
+            .map(|(name, gains)| Preset {
+                name: name.to_string(),
+                gains: *gains,
+            })
+            .collect();
+
+        for (i, line) in text.lines().enumerate() {
+            match Self::parse_preset(line) {
+                Ok(Some(preset)) => match presets.iter_mut().find(|p| p.name == preset.name) {
+                    Some(builtin) => *builtin = preset,
+                    None => presets.push(preset),
+                },
+                Ok(None) => (),
+                Err(e) => glib::g_warning!("equalizer", "Line {}: {}", i + 1, e),
+            }
+        }
+
+        let current = presets.iter().position(|p| p.name == active).unwrap_or(0);
+        let gains = presets[current].gains;
+        Self {
+            presets,
+            current,
+            gains,
+        }
+    }
+
+    /// Parses a line of the presets file: the name of the preset, a
+    /// colon and the gains of the ten bands in dB separated by spaces,
+    /// e.g. "rock: 5 4 3 1 -1 -1 1 3 4 5". Returns None for blank lines
+    /// and comments starting with '#'.
+    fn parse_preset(line: &str) -> Result<Option<Preset>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (name, gains) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected \"name: gains\""))?;
+        let gains: Vec<f64> = gains
+            .split_whitespace()
+            .map(|gain| {
+                gain.parse()
+                    .with_context(|| format!("Invalid gain {}", gain))
+            })
+            .collect::<Result<_>>()?;
+        let gains: [f64; BANDS] = gains
+            .try_into()
+            .map_err(|_| anyhow!("Expected {} gains", BANDS))?;
+
+        match gains.iter().all(|g| (MIN_GAIN..=MAX_GAIN).contains(g)) {
+            true => Ok(Some(Preset {
+                name: name.trim().to_string(),
+                gains,
+            })),
+            false => Err(anyhow!(
+                "Gains must be between {} and {}",
+                MIN_GAIN,
+                MAX_GAIN
+            )),
+        }
+    }
+
+    /// Returns the gains currently applied, from the lowest band.
+    pub fn gains(&self) -> [f64; BANDS] {
+        self.gains
+    }
+
+    /// Returns the active preset and the gains for showing them.
+    pub fn settings(&self) -> Settings {
+        let preset = &self.presets[self.current];
+        Settings {
+            preset: preset.name.to_string(),
+            modified: preset.gains != self.gains,
+            gains: self.gains,
+        }
+    }
+
+    /// Switches to the next preset, undoing any adjustments.
+    pub fn next_preset(&mut self) {
+        self.current = (self.current + 1) % self.presets.len();
+        self.gains = self.presets[self.current].gains;
+    }
+
+    /// Raises or lowers the gain of `band` by `step` dB.
+    pub fn adjust(&mut self, band: usize, step: f64) {
+        if let Some(gain) = self.gains.get_mut(band) {
+            *gain = (*gain + step).clamp(MIN_GAIN, MAX_GAIN);
+        }
+    }
+
+    /// Returns the path of the file the active preset is kept in
+    /// between runs.
+    fn preset_file() -> PathBuf {
+        glib::user_data_dir().join("soi").join("equalizer-preset")
+    }
+
+    /// Stores the name of the active preset for the next run.
+    pub fn save(&self) -> Result<()> {
+        let file = Self::preset_file();
+        let dir = file.parent().context("Preset file has no directory")?;
+        std::fs::create_dir_all(dir).with_context(|| format!("{:?}", dir))?;
+        std::fs::write(&file, format!("{}\n", self.presets[self.current].name))
+            .with_context(|| format!("{:?}", file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // User presets are added after the built-in ones, or replace one
+    // of the same name, and broken lines are skipped
+    fn user_presets_read() {
+        let text = "# My presets\n\
+                    rock: 1 1 1 1 1 1 1 1 1 1\n\
+                    late night: -3 -2 0 0 0 0 0 -2 -4 -6\n\
+                    too short: 1 2 3\n\
+                    too loud: 20 0 0 0 0 0 0 0 0 0\n\
+                    \n";
+        let eq = Equalizer::new(text, "late night");
+
+        assert_eq!(eq.presets.len(), BUILTIN_PRESETS.len() + 1);
+        assert_eq!(eq.presets[1].gains, [1.0; BANDS]);
+        assert_eq!(eq.settings().preset, "late night");
+        assert_eq!(eq.gains()[9], -6.0);
+    }
+
+    #[test]
+    // Adjusting a band marks the preset modified until the next preset
+    // is chosen, and gains stay within the range of the element
+    fn bands_adjusted() {
+        let mut eq = Equalizer::new("", "no such preset");
+        assert_eq!(eq.settings().preset, "flat");
+
+        eq.adjust(0, 3.0);
+        eq.adjust(9, -30.0);
+        eq.adjust(BANDS, 1.0);
+        let settings = eq.settings();
+        assert!(settings.modified);
+        assert_eq!((settings.gains[0], settings.gains[9]), (3.0, MIN_GAIN));
+
+        eq.next_preset();
+        assert_eq!(eq.settings().preset, "rock");
+        assert!(!eq.settings().modified);
+    }
+}
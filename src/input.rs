@@ -1,35 +1,139 @@
 //! Reads and interprets user key presses.
 
+use crate::seek::Seek;
+
+use anyhow::{anyhow, Result};
 use termion::event::Key;
 use termion::input::TermRead;
 
 /// Valid user actions the main program needs to act on.
 pub enum UserInput {
     Help,
+    Lyrics,
+    Equalizer,
+    NextPreset,
+    ScrollUp,
+    ScrollDown,
     Mute,
+    Faster,
+    Slower,
+    NormalSpeed,
+    VolumeUp,
+    VolumeDown,
+    SleepTimer,
+    StopAfterSong,
+    StopAfterAlbum,
     Pause,
     Stop,
     Next,
     Prev,
     SeekBackward,
     SeekForward,
+    LongSeekBackward,
+    LongSeekForward,
+    LoopStart,
+    LoopEnd,
+    ClearLoop,
+    Seek(Seek),
+    /// The command line to show while a command is typed after ':',
+    /// or an error if it was invalid. None hides it.
+    Prompt(Option<String>),
+    NextChapter,
+    PrevChapter,
+    RateUp,
+    RateDown,
+    Favourite,
 }
 
-/// Interprets user key presses as `UserInput` variants.
-pub fn handle_user_input() -> Option<UserInput> {
-    match read_key_press()? {
+/// Interprets user key presses as `UserInput` variants. `command` is
+/// the command being typed after ':', if any.
+pub fn handle_user_input(command: &mut Option<String>) -> Option<UserInput> {
+    let key = read_key_press()?;
+    if command.is_some() {
+        return edit_command(command, key);
+    }
+
+    match key {
         Key::Char('?') => Some(UserInput::Help),
+        Key::Char('L') => Some(UserInput::Lyrics),
+        Key::Char('e') => Some(UserInput::Equalizer),
+        Key::Char('p') => Some(UserInput::NextPreset),
+        Key::PageUp => Some(UserInput::ScrollUp),
+        Key::PageDown => Some(UserInput::ScrollDown),
         Key::Char('m') => Some(UserInput::Mute),
+        Key::Char('}') => Some(UserInput::Faster),
+        Key::Char('{') => Some(UserInput::Slower),
+        Key::Backspace => Some(UserInput::NormalSpeed),
+        Key::Char('+') | Key::Char('*') => Some(UserInput::VolumeUp),
+        Key::Char('-') | Key::Char('/') => Some(UserInput::VolumeDown),
+        Key::Char('s') => Some(UserInput::SleepTimer),
+        Key::Char('t') => Some(UserInput::StopAfterSong),
+        Key::Char('T') => Some(UserInput::StopAfterAlbum),
         Key::Char(' ') => Some(UserInput::Pause),
         Key::Char('q') => Some(UserInput::Stop),
         Key::Char('h') | Key::Left => Some(UserInput::SeekBackward),
         Key::Char('j') | Key::Down => Some(UserInput::Next),
         Key::Char('k') | Key::Up => Some(UserInput::Prev),
         Key::Char('l') | Key::Right => Some(UserInput::SeekForward),
+        Key::Char('<') => Some(UserInput::LongSeekBackward),
+        Key::Char('>') => Some(UserInput::LongSeekForward),
+        Key::Char(c @ '0'..='9') => {
+            let percent = c.to_digit(10)? as u8 * 10;
+            Some(UserInput::Seek(Seek::Percent(percent)))
+        }
+        Key::Home => Some(UserInput::Seek(Seek::To(std::time::Duration::ZERO))),
+        Key::End => Some(UserInput::Seek(Seek::End)),
+        Key::Char(':') => {
+            *command = Some(String::new());
+            Some(UserInput::Prompt(Some(":".to_string())))
+        }
+        Key::Char('a') => Some(UserInput::LoopStart),
+        Key::Char('b') => Some(UserInput::LoopEnd),
+        Key::Char('A') => Some(UserInput::ClearLoop),
+        Key::Char(']') => Some(UserInput::NextChapter),
+        Key::Char('[') => Some(UserInput::PrevChapter),
+        Key::Char('r') => Some(UserInput::RateUp),
+        Key::Char('R') => Some(UserInput::RateDown),
+        Key::Char('f') => Some(UserInput::Favourite),
         _ => None,
     }
 }
 
+/// Adds `key` to the command being typed, or runs the command on
+/// Enter. Escape or erasing the whole command cancels it.
+fn edit_command(command: &mut Option<String>, key: Key) -> Option<UserInput> {
+    let text = command.as_mut()?;
+    match key {
+        Key::Char('\n') => {
+            let text = command.take()?;
+            return Some(match parse_command(&text) {
+                Ok(input) => input,
+                Err(e) => UserInput::Prompt(Some(format!("{:#}", e))),
+            });
+        }
+        Key::Esc => *command = None,
+        Key::Backspace => {
+            if text.pop().is_none() {
+                *command = None;
+            }
+        }
+        Key::Char(c) => text.push(c),
+        _ => return None,
+    }
+    Some(UserInput::Prompt(
+        command.as_ref().map(|t| format!(":{}", t)),
+    ))
+}
+
+/// Parses a command typed after ':', e.g. "seek 3:25".
+fn parse_command(text: &str) -> Result<UserInput> {
+    let (name, arg) = text.trim().split_once(' ').unwrap_or((text.trim(), ""));
+    match name {
+        "seek" => Ok(UserInput::Seek(Seek::parse(arg)?)),
+        _ => Err(anyhow!("Unknown command :{}", text.trim())),
+    }
+}
+
 /// Reads single key press from stdin(), returning None if no input
 /// is available.
 fn read_key_press() -> Option<Key> {
@@ -0,0 +1,290 @@
+//! Loudness analysis of files without ReplayGain tags, and a cache
+//! of the results.
+
+use crate::config::ReplayGain;
+use crate::song::Song;
+use crate::traits::{PathToURI, UnwrappedMutex};
+
+use anyhow::{anyhow, Context, Result};
+use gst::prelude::*;
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Gain and peak of a track and the album it is on, as they would be
+/// in ReplayGain tags.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loudness {
+    /// Gain in dB bringing the track to the reference loudness
+    pub track_gain: f64,
+    /// Highest sample of the track, 1.0 being full scale
+    pub track_peak: f64,
+    pub album_gain: f64,
+    pub album_peak: f64,
+}
+
+impl Loudness {
+    /// Returns the track or album gain, depending on `mode`.
+    pub fn gain(&self, mode: ReplayGain) -> f64 {
+        match mode {
+            ReplayGain::Album => self.album_gain,
+            _ => self.track_gain,
+        }
+    }
+}
+
+/// Returns the gain and peak of an album from the gain, peak and
+/// duration of each of its tracks. The gain is that of the tracks'
+/// average energy, weighted by their duration.
+fn album_loudness(tracks: &[(f64, f64, Duration)]) -> (f64, f64) {
+    let total: f64 = tracks.iter().map(|(_, _, d)| d.as_secs_f64()).sum();
+    let energy: f64 = tracks
+        .iter()
+        .map(|(gain, _, d)| d.as_secs_f64() * 10f64.powf(-gain / 10.0))
+        .sum();
+    let peak = tracks.iter().map(|(_, peak, _)| *peak).fold(0.0, f64::max);
+
+    match total > 0.0 && energy > 0.0 {
+        true => (-10.0 * (energy / total).log10(), peak),
+        false => (0.0, peak),
+    }
+}
+
+/// Loudness of files analyzed earlier, keyed by content hash (see
+/// `ratings::content_hash()`), kept in `$XDG_CACHE_HOME/soi/loudness`.
+#[derive(Clone, Default)]
+pub struct Cache {
+    /// Path of the cache file
+    file: PathBuf,
+    entries: HashMap<String, (PathBuf, Loudness)>,
+}
+
+impl Cache {
+    /// Reads the cache file. If it does not exist yet, nothing has
+    /// been analyzed.
+    pub fn load() -> Self {
+        let file = glib::user_cache_dir().join("soi").join("loudness");
+        let text = std::fs::read_to_string(&file).unwrap_or_default();
+        Self::parse(file, &text)
+    }
+
+    /// Parses the cache in `text`, with one file per line: hash, track
+    /// gain, track peak, album gain, album peak and path, separated
+    /// by tabs.
+    fn parse(file: PathBuf, text: &str) -> Self {
+        let mut entries = HashMap::new();
+
+        for line in text.lines() {
+            let fields: Vec<&str> = line.splitn(6, '\t').collect();
+            if let [hash, values @ .., path] = &fields[..] {
+                let values: Vec<f64> = values.iter().filter_map(|v| v.parse().ok()).collect();
+                if let [track_gain, track_peak, album_gain, album_peak] = values[..] {
+                    let loudness = Loudness {
+                        track_gain,
+                        track_peak,
+                        album_gain,
+                        album_peak,
+                    };
+                    entries.insert(hash.to_string(), (PathBuf::from(path), loudness));
+                }
+            }
+        }
+
+        Self { file, entries }
+    }
+
+    /// Returns the loudness of the file with the given content hash,
+    /// if it has been analyzed.
+    pub fn get(&self, hash: Option<&str>) -> Option<Loudness> {
+        self.entries.get(hash?).map(|(_, loudness)| *loudness)
+    }
+
+    /// Returns the contents of the cache file, sorted by path.
+    fn serialize(&self) -> String {
+        self.entries
+            .iter()
+            .sorted_by(|a, b| (a.1).0.cmp(&(b.1).0))
+            .map(|(hash, (path, l))| {
+                format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\n",
+                    hash,
+                    l.track_gain,
+                    l.track_peak,
+                    l.album_gain,
+                    l.album_peak,
+                    path.display()
+                )
+            })
+            .collect()
+    }
+
+    /// Writes the cache, replacing the file only once it has been
+    /// written completely.
+    fn save(&self) -> Result<()> {
+        let dir = self.file.parent().context("Cache file has no directory")?;
+        std::fs::create_dir_all(dir).with_context(|| format!("{:?}", dir))?;
+
+        let tmp = self.file.with_extension("tmp");
+        std::fs::write(&tmp, self.serialize()).with_context(|| format!("{:?}", tmp))?;
+        std::fs::rename(&tmp, &self.file).with_context(|| format!("{:?}", self.file))?;
+        Ok(())
+    }
+}
+
+/// Returns the songs that have no ReplayGain tags and have not been
+/// analyzed yet as (path, content hash) pairs, grouped by album.
+pub fn pending<'a>(
+    songs: impl Iterator<Item = &'a Song>,
+    cache: &Cache,
+) -> Vec<Vec<(PathBuf, String)>> {
+    songs
+        .filter(|song| !song.replaygain_tagged)
+        .filter_map(|song| {
+            let hash = song.content_hash.as_ref()?;
+            match cache.get(Some(hash)) {
+                Some(_) => None,
+                None => Some((
+                    song.album_info.to_string(),
+                    song.path.to_path_buf(),
+                    hash.to_string(),
+                )),
+            }
+        })
+        .group_by(|(album, _, _)| album.to_string())
+        .into_iter()
+        .map(|(_, tracks)| tracks.map(|(_, path, hash)| (path, hash)).collect())
+        .collect()
+}
+
+/// Analyzes `albums`, one track at a time, and stores the results in
+/// `cache` after each album. With `report` set, the progress is printed.
+pub fn analyze(albums: Vec<Vec<(PathBuf, String)>>, cache: &Arc<Mutex<Cache>>, report: bool) {
+    let total: usize = albums.iter().map(Vec::len).sum();
+    let mut done = 0;
+
+    for album in albums {
+        let mut tracks = Vec::new();
+        for (path, hash) in album {
+            done += 1;
+            if report {
+                eprintln!("[{}/{}] {}", done, total, path.display());
+            }
+
+            match analyze_track(&path) {
+                Ok(track) => tracks.push((path, hash, track)),
+                Err(e) => glib::g_debug!("loudness", "{:?}: {}", path, e),
+            }
+        }
+
+        let loudness: Vec<_> = tracks.iter().map(|(_, _, track)| *track).collect();
+        let (album_gain, album_peak) = album_loudness(&loudness);
+
+        let mut cache = cache.lockk();
+        for (path, hash, (track_gain, track_peak, _)) in tracks {
+            let loudness = Loudness {
+                track_gain,
+                track_peak,
+                album_gain,
+                album_peak,
+            };
+            cache.entries.insert(hash, (path, loudness));
+        }
+        if let Err(e) = cache.save() {
+            glib::g_warning!("loudness", "Unable to save loudness cache: {:#}", e);
+        }
+    }
+}
+
+/// Decodes the file at `path` as fast as possible through `rganalysis`
+/// and returns its gain, peak and duration.
+fn analyze_track(path: &Path) -> Result<(f64, f64, Duration)> {
+    let pipeline = gst::parse_launch(
+        "uridecodebin name=src ! audioconvert ! audioresample ! rganalysis ! fakesink sync=false",
+    )?;
+    let src = pipeline
+        .downcast_ref::<gst::Bin>()
+        .and_then(|bin| bin.by_name("src"))
+        .context("uridecodebin")?;
+    src.set_property("uri", path.to_uri())?;
+
+    let bus = pipeline.bus().context("Analysis pipeline bus")?;
+    pipeline.set_state(gst::State::Playing)?;
+
+    let mut gain = None;
+    let mut peak = None;
+    let result = loop {
+        let msg = bus.timed_pop_filtered(
+            gst::ClockTime::NONE,
+            &[
+                gst::MessageType::Tag,
+                gst::MessageType::Eos,
+                gst::MessageType::Error,
+            ],
+        );
+
+        match msg.as_ref().map(|m| m.view()) {
+            Some(gst::MessageView::Tag(msg)) => {
+                let tags = msg.tags();
+                gain = tags.get::<gst::tags::TrackGain>().map(|v| v.get()).or(gain);
+                peak = tags.get::<gst::tags::TrackPeak>().map(|v| v.get()).or(peak);
+            }
+            Some(gst::MessageView::Eos(_)) => {
+                let duration: Option<Duration> = pipeline
+                    .query_duration::<gst::ClockTime>()
+                    .or_else(|| pipeline.query_position::<gst::ClockTime>())
+                    .map(Into::into);
+                break match (gain, peak, duration) {
+                    (Some(gain), Some(peak), Some(duration)) => Ok((gain, peak, duration)),
+                    _ => Err(anyhow!("No loudness measured")),
+                };
+            }
+            Some(gst::MessageView::Error(e)) => break Err(anyhow!("{}", e.error())),
+            _ => break Err(anyhow!("Analysis pipeline stopped")),
+        }
+    };
+
+    pipeline.set_state(gst::State::Null)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // A louder track weighs more in the album gain, and its peak
+    // becomes the album peak
+    fn album_gain_from_tracks() {
+        let minute = Duration::from_secs(60);
+        let (gain, peak) = album_loudness(&[(-3.0, 0.5, minute), (-3.0, 0.9, minute)]);
+        assert!((gain - -3.0).abs() < 1e-9);
+        assert_eq!(peak, 0.9);
+
+        let (gain, _) = album_loudness(&[(0.0, 0.5, minute), (-10.0, 0.5, minute)]);
+        assert!(gain < -5.0 && gain > -10.0);
+        assert_eq!(album_loudness(&[]), (0.0, 0.0));
+    }
+
+    #[test]
+    // Entries survive being written and read back
+    fn cache_round_trip() {
+        let mut cache = Cache::parse(PathBuf::from("loudness"), "");
+        let loudness = Loudness {
+            track_gain: -6.5,
+            track_peak: 0.98,
+            album_gain: -7.25,
+            album_peak: 1.0,
+        };
+        cache.entries.insert(
+            "abc".to_string(),
+            (PathBuf::from("/music/a.flac"), loudness),
+        );
+
+        let cache = Cache::parse(PathBuf::from("loudness"), &cache.serialize());
+        assert_eq!(cache.get(Some("abc")), Some(loudness));
+        assert_eq!(cache.get(Some("def")), None);
+        assert_eq!(cache.get(None), None);
+    }
+}
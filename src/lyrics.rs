@@ -0,0 +1,183 @@
+//! Song lyrics, either synchronized (LRC) or plain text.
+
+use std::path::Path;
+use std::time::Duration;
+
+/// Lyrics of a song, read from an .lrc file or the lyrics tag.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Lyrics {
+    /// Lines with the time they are sung at, in order
+    Synced(Vec<(Duration, String)>),
+    /// Lines without timestamps
+    Unsynced(Vec<String>),
+}
+
+impl Lyrics {
+    /// Reads the lyrics in `<basename>.lrc` next to the audio file
+    /// at `path`. If there is no such file, `tag` (the lyrics tag
+    /// of the file) is used instead.
+    pub fn load(path: &Path, tag: Option<&str>) -> Option<Self> {
+        let sidecar = std::fs::read_to_string(path.with_extension("lrc")).ok();
+        let text = sidecar.as_deref().or(tag)?;
+
+        match text.trim().is_empty() {
+            true => None,
+            false => Some(Self::parse(text)),
+        }
+    }
+
+    /// Parses LRC formatted lyrics. A line may have several timestamps
+    /// ("[00:12.00][01:30.50]Chorus"), and an "[offset:+/-ms]" tag
+    /// shifts all of them. If no line has a timestamp, the text is
+    /// returned as `Lyrics::Unsynced`.
+    pub fn parse(text: &str) -> Self {
+        let mut offset: i64 = 0;
+        let mut synced = Vec::new();
+        let mut unsynced = Vec::new();
+
+        for line in text.lines().map(str::trim) {
+            let mut rest = line;
+            let mut timestamps = Vec::new();
+
+            while let Some((tag, after)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+                if let Some(time) = parse_timestamp(tag) {
+                    timestamps.push(time);
+                } else if let Some(value) = tag.strip_prefix("offset:") {
+                    offset = value.trim().parse().unwrap_or(0);
+                } else if !timestamps.is_empty() || tag.contains(':') {
+                    // Other ID tags, e.g. [ar:Artist], are not shown
+                } else {
+                    break;
+                }
+                rest = after;
+            }
+
+            let text = strip_word_timestamps(rest);
+            if timestamps.is_empty() {
+                if rest.len() == line.len() {
+                    unsynced.push(text);
+                }
+            } else {
+                synced.extend(timestamps.into_iter().map(|t| (t, text.to_string())));
+            }
+        }
+
+        if synced.is_empty() {
+            // Drop leading and trailing empty lines
+            let start = unsynced.iter().position(|l| !l.is_empty()).unwrap_or(0);
+            let end = unsynced
+                .iter()
+                .rposition(|l| !l.is_empty())
+                .map_or(0, |i| i + 1);
+            return Self::Unsynced(unsynced.get(start..end).unwrap_or_default().to_vec());
+        }
+
+        // A positive offset makes the lyrics appear sooner
+        for (time, _) in synced.iter_mut() {
+            let ms = time.as_millis() as i64 - offset;
+            *time = Duration::from_millis(ms.max(0) as u64);
+        }
+        synced.sort_by_key(|(time, _)| *time);
+        Self::Synced(synced)
+    }
+
+    /// Returns the lines of the lyrics, without timestamps.
+    pub fn lines(&self) -> Vec<&str> {
+        match self {
+            Self::Synced(lines) => lines.iter().map(|(_, l)| l.as_str()).collect(),
+            Self::Unsynced(lines) => lines.iter().map(String::as_str).collect(),
+        }
+    }
+
+    /// Returns the index of the line being sung at `position`, or None
+    /// if the lyrics are not synchronized or the first line is yet to
+    /// come.
+    pub fn current_line(&self, position: Duration) -> Option<usize> {
+        match self {
+            Self::Synced(lines) => lines.iter().rposition(|(time, _)| *time <= position),
+            Self::Unsynced(_) => None,
+        }
+    }
+}
+
+/// Parses an LRC timestamp, "mm:ss", "mm:ss.xx" or "mm:ss:xx".
+fn parse_timestamp(tag: &str) -> Option<Duration> {
+    let (minutes, seconds) = tag.split_once(':')?;
+    let minutes: u64 = minutes.trim().parse().ok()?;
+
+    // Some files separate hundredths with a colon
+    let seconds = seconds.replacen(':', ".", 1);
+    if !seconds.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let seconds: f64 = seconds.parse().ok()?;
+
+    Some(Duration::from_secs(minutes * 60) + Duration::from_secs_f64(seconds))
+}
+
+/// Removes the word timestamps of enhanced LRC, "<mm:ss.xx>", from `text`.
+fn strip_word_timestamps(text: &str) -> String {
+    let mut ret = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) if parse_timestamp(&rest[start + 1..start + end]).is_some() => {
+                ret.push_str(&rest[..start]);
+                rest = &rest[start + end + 1..];
+            }
+            _ => {
+                ret.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    ret.push_str(rest);
+    ret.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Lines with several timestamps are repeated at each of them,
+    // and the lines are ordered by time
+    fn synced_lyrics_parsed() {
+        let lyrics = Lyrics::parse(
+            "[ar:Artist]\n[ti:Title]\n[00:01.00]First\n[00:03.50][00:10.00]Chorus\n[00:05:25]Second\n",
+        );
+        let expected = vec![
+            (Duration::from_millis(1000), "First".to_string()),
+            (Duration::from_millis(3500), "Chorus".to_string()),
+            (Duration::from_millis(5250), "Second".to_string()),
+            (Duration::from_millis(10000), "Chorus".to_string()),
+        ];
+        assert_eq!(lyrics, Lyrics::Synced(expected));
+
+        assert_eq!(lyrics.current_line(Duration::from_millis(500)), None);
+        assert_eq!(lyrics.current_line(Duration::from_millis(4000)), Some(1));
+        assert_eq!(lyrics.current_line(Duration::from_secs(60)), Some(3));
+    }
+
+    #[test]
+    // A positive offset makes lines appear sooner
+    fn offset_applied() {
+        let lyrics = Lyrics::parse("[offset:+500]\n[00:01.00]<00:01.00>One <00:01.50>two\n");
+        let expected = vec![(Duration::from_millis(500), "One two".to_string())];
+        assert_eq!(lyrics, Lyrics::Synced(expected));
+
+        let lyrics = Lyrics::parse("[offset:-250]\n[00:01.00]One\n");
+        let expected = vec![(Duration::from_millis(1250), "One".to_string())];
+        assert_eq!(lyrics, Lyrics::Synced(expected));
+    }
+
+    #[test]
+    // Text without timestamps is kept as it is, empty lines included
+    fn unsynced_lyrics_parsed() {
+        let lyrics = Lyrics::parse("\nFirst verse\n\n[Chorus]\nSecond verse\n\n");
+        let expected = vec!["First verse", "", "[Chorus]", "Second verse"];
+        assert_eq!(lyrics.lines(), expected);
+        assert_eq!(lyrics.current_line(Duration::from_secs(1)), None);
+    }
+}
@@ -1,13 +1,24 @@
 //! A music player for the pre-streaming era.
 
 mod backend;
+mod config;
+mod cover;
+mod crossfade;
+mod equalizer;
 mod input;
+mod loudness;
+mod lyrics;
 mod output;
+mod pattern;
 mod playlist;
+mod ratings;
+mod release_date;
+mod seek;
+mod sink;
 mod song;
 mod traits;
 
-use backend::BackendMessage;
+use backend::{BackendMessage, StopAfter};
 use dbus::blocking::Connection;
 use input::{handle_user_input, UserInput};
 use std::sync::{Arc, Mutex};
@@ -15,17 +26,44 @@ use std::thread::sleep;
 use std::time::Duration;
 use traits::{ArgFiles, UnwrappedMutex};
 
+/// Number of songs in a row that may fail to play before giving up
+const MAX_FAILURES: usize = 5;
+
 fn main() -> anyhow::Result<()> {
-    handle_cmd_line_flags();
+    let files = handle_cmd_line_flags();
+
+    // Use the user's locale for sorting artist and album names
+    unsafe {
+        libc::setlocale(libc::LC_COLLATE, b"\0".as_ptr() as *const libc::c_char);
+    }
 
     let ctx = glib::MainContext::default();
     let _guard = ctx.acquire();
     let mainloop = glib::MainLoop::new(Some(&ctx), false);
 
-    let (mut backend, backend_rx) = backend::Backend::new();
-    let playlist = Arc::new(Mutex::new(playlist::Playlist::from(
-        &std::env::args().files()?,
-    )));
+    if config::get().list_devices {
+        gst::init()?;
+        return sink::list_devices();
+    }
+
+    let loudness = Arc::new(Mutex::new(loudness::Cache::load()));
+    if config::get().scan_loudness {
+        gst::init()?;
+        let playlist = playlist::Playlist::from(&files.files()?);
+        let pending = loudness::pending(playlist.iter().map(|(_, s)| s), &loudness.lockk());
+        loudness::analyze(pending, &loudness, true);
+        return Ok(());
+    }
+
+    let (mut backend, backend_rx) = backend::Backend::new(loudness.clone());
+    let playlist = Arc::new(Mutex::new(playlist::Playlist::from(&files.files()?)));
+
+    // Analyze files without ReplayGain tags in the background, so that
+    // they play at the right volume from now on
+    if config::get().replaygain != config::ReplayGain::Off {
+        let pending = loudness::pending(playlist.lockk().iter().map(|(_, s)| s), &loudness.lockk());
+        std::thread::spawn(move || loudness::analyze(pending, &loudness, false));
+    }
     let output = Arc::new(Mutex::new(output::Output::new()));
 
     // Quick and dirty: block GNOME from suspending during playback
@@ -43,12 +81,15 @@ fn main() -> anyhow::Result<()> {
 
     // New thread for waiting for user input
     let (input_tx, input_rx) = glib::MainContext::channel(glib::source::Priority::default());
-    std::thread::spawn(move || loop {
-        match handle_user_input() {
-            None => sleep(Duration::from_millis(100)),
-            Some(x) => input_tx
-                .send(x)
-                .expect("Failed to send user input to main thread"),
+    std::thread::spawn(move || {
+        let mut command = None;
+        loop {
+            match handle_user_input(&mut command) {
+                None => sleep(Duration::from_millis(100)),
+                Some(x) => input_tx
+                    .send(x)
+                    .expect("Failed to send user input to main thread"),
+            }
         }
     });
 
@@ -56,15 +97,77 @@ fn main() -> anyhow::Result<()> {
     input_rx.attach(
         None,
         glib::clone!(@strong backend, @strong playlist, @strong output => move |msg| {
+            // An error from the last command is shown until the next key
+            if !matches!(msg, UserInput::Prompt(_)) {
+                output.lockk().set_prompt(None);
+            }
             match msg {
                 UserInput::Help => output.lockk().toggle_help(),
+                UserInput::Lyrics => output.lockk().toggle_lyrics(),
+                UserInput::Equalizer => output.lockk().toggle_equalizer(),
+                UserInput::NextPreset => backend.next_preset(),
+                // The arrow keys adjust the equalizer while it is shown
+                UserInput::Prev if output.lockk().showing_equalizer() => {
+                    output.lockk().select_band(-1)
+                }
+                UserInput::Next if output.lockk().showing_equalizer() => {
+                    output.lockk().select_band(1)
+                }
+                UserInput::SeekBackward if output.lockk().showing_equalizer() => {
+                    backend.adjust_band(output.lockk().selected_band(), -1.0)
+                }
+                UserInput::SeekForward if output.lockk().showing_equalizer() => {
+                    backend.adjust_band(output.lockk().selected_band(), 1.0)
+                }
+                UserInput::ScrollUp => output.lockk().scroll_lyrics(-5),
+                UserInput::ScrollDown => output.lockk().scroll_lyrics(5),
                 UserInput::Mute => backend.toggle_mute(),
+                // Without scaletempo, the pitch would change with the speed
+                UserInput::Faster | UserInput::Slower | UserInput::NormalSpeed
+                    if !backend.can_change_rate() =>
+                {
+                    output.lockk().set_prompt(Some(
+                        "Speed cannot be changed, `scaletempo` is not installed".to_string(),
+                    ));
+                    Ok(())
+                }
+                UserInput::Faster => backend.change_rate(0.1),
+                UserInput::Slower => backend.change_rate(-0.1),
+                UserInput::NormalSpeed => backend.change_rate(0.0),
+                UserInput::VolumeUp => backend.change_volume(5),
+                UserInput::VolumeDown => backend.change_volume(-5),
+                UserInput::SleepTimer => backend.cycle_sleep_timer(),
+                UserInput::StopAfterSong => backend.toggle_stop_after(StopAfter::Song),
+                UserInput::StopAfterAlbum => backend.toggle_stop_after(StopAfter::Album),
                 UserInput::Pause => backend.toggle_pause(),
                 UserInput::Stop => backend.stop(),
                 UserInput::Next => backend.play(playlist.lockk().next()),
                 UserInput::Prev => backend.play(playlist.lockk().prev()),
-                UserInput::SeekBackward => backend.seek_backward(),
-                UserInput::SeekForward => backend.seek_forward(),
+                UserInput::SeekBackward => {
+                    backend.seek_by(playlist.lockk().current(), config::get().seek_step, false)
+                }
+                UserInput::SeekForward => {
+                    backend.seek_by(playlist.lockk().current(), config::get().seek_step, true)
+                }
+                UserInput::LongSeekBackward => {
+                    backend.seek_by(playlist.lockk().current(), config::get().long_seek_step, false)
+                }
+                UserInput::LongSeekForward => {
+                    backend.seek_by(playlist.lockk().current(), config::get().long_seek_step, true)
+                }
+                UserInput::Seek(seek) => backend.seek(playlist.lockk().current(), seek),
+                UserInput::Prompt(text) => {
+                    output.lockk().set_prompt(text);
+                    Ok(())
+                }
+                UserInput::LoopStart => backend.mark_loop_start(),
+                UserInput::LoopEnd => backend.mark_loop_end(),
+                UserInput::ClearLoop => backend.clear_loop(),
+                UserInput::NextChapter => backend.next_chapter(playlist.lockk().current()),
+                UserInput::PrevChapter => backend.prev_chapter(playlist.lockk().current()),
+                UserInput::RateUp => playlist.lockk().rate_current(1),
+                UserInput::RateDown => playlist.lockk().rate_current(-1),
+                UserInput::Favourite => playlist.lockk().toggle_favourite(),
              }.expect("Error while handling user input");
             glib::Continue(true)
         }),
@@ -74,6 +177,7 @@ fn main() -> anyhow::Result<()> {
     backend.play(playlist.lockk().current())?;
 
     // Handle messages from backend
+    let mut failures = 0;
     backend_rx.attach(
         None,
         glib::clone!(@strong mainloop => move |msg| {
@@ -88,9 +192,41 @@ fn main() -> anyhow::Result<()> {
                     mainloop.quit();
                 }
                 BackendMessage::RequestNextSong => {
-                    backend.enqueue(playlist.lockk().peek());
+                    // Nothing is queued when playback is to stop after
+                    // the current song, so that it ends there
+                    let playlist = playlist.lockk();
+                    let next = playlist.peek().filter(|next| !backend.stops_before(next));
+                    backend.enqueue(next);
+                }
+                BackendMessage::Error(path, error) => {
+                    // Unplayable songs are skipped, unless none of the
+                    // last few could be played either
+                    output.lockk().mark_failed(&path, &error);
+                    failures += 1;
+                    let mut playlist = playlist.lockk();
+                    match (failures < MAX_FAILURES, playlist.next()) {
+                        (true, Some(song)) => {
+                            backend.play(Some(song))
+                                .expect("Error while skipping to the next song");
+                        }
+                        (true, None) => {
+                            output.lockk().cleanup();
+                            mainloop.quit();
+                        }
+                        (false, _) => {
+                            output.lockk().cleanup();
+                            eprintln!(
+                                "Stopped after {} songs in a row could not be played",
+                                failures
+                            );
+                            mainloop.quit();
+                        }
+                    }
                 }
                 BackendMessage::State(state) => {
+                    if state.position > Duration::ZERO {
+                        failures = 0;
+                    }
                   output.lockk().refresh(state, &playlist.lockk())
                         .ok(); // ignore any output errors
                 }
@@ -103,29 +239,227 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn print_version_and_exit() {
+pub fn print_version_and_exit() -> ! {
     println!("soi {}", env!("CARGO_PKG_VERSION"));
     std::process::exit(1);
 }
 
-pub fn print_usage_and_exit() {
+pub fn print_usage_and_exit() -> ! {
     eprintln!("Usage: soi FILES...\n");
 
     eprintln!("      --help                   Show this help message");
     eprintln!("      --version                Display version information");
+    eprintln!("      --verbose                List files that cannot be played");
+    eprintln!("      --ignore-articles        Sort \"The Beatles\" under B");
+    eprintln!("      --various NAME           Treat albums by NAME as compilations");
+    eprintln!("      --date release|original  Show and sort albums by this date");
+    eprintln!("      --cover MODE             Draw album covers with kitty, iterm,");
+    eprintln!("                               sixel or blocks, or turn them off");
+    eprintln!("      --artwork NAMES          Look for album artwork in files with these");
+    eprintln!("                               comma-separated names, e.g. cover,folder");
+    eprintln!("      --replaygain MODE        Even out volume with track or album gain,");
+    eprintln!("                               or turn it off (default: album)");
+    eprintln!("      --preamp DB              Add DB decibels to the ReplayGain adjustment");
+    eprintln!("      --fallback-gain DB       Gain for files without ReplayGain tags");
+    eprintln!("      --sink SINK              Play through pulse, pipewire, alsa or jack,");
+    eprintln!("                               or write a WAV file with file");
+    eprintln!("      --device NAME            Output device of the sink, or the WAV file");
+    eprintln!("      --list-devices           List audio output devices and exit");
+    eprintln!("      --crossfade SECONDS      Crossfade between songs instead of playing");
+    eprintln!("                               them gaplessly");
+    eprintln!("      --crossfade-albums       Crossfade only between albums");
+    eprintln!("      --volume N               Start playback at N percent volume");
+    eprintln!("      --sleep TIME             Fade out and stop after TIME, e.g. 30m");
+    eprintln!("      --seek-step SECONDS      Seek by SECONDS with h and l (default: 5)");
+    eprintln!("      --long-seek-step SECONDS Seek by SECONDS with < and > (default: 60)");
+    eprintln!("      --scan-loudness          Analyze files without ReplayGain tags and exit");
+    eprintln!("      --min-rating N           Only play songs rated at least N stars");
+    eprintln!("      --favourites             Only play songs marked as favourites");
+    eprintln!("      --artist NAME            Only play songs by artists matching NAME");
+    eprintln!("      --genre NAME             Only play songs in genres matching NAME");
+    eprintln!("      --composer NAME          Only play songs by composers matching NAME");
+    eprintln!("      --separator SEP          Separate multiple artists with SEP");
+    eprintln!("      --pattern PATTERN        Read missing tags from file names, e.g.");
+    eprintln!("                               \"%artist% - %album% - %track% - %title%\"");
+    eprintln!("      --dir-pattern PATTERN    Read missing album tags from directory names,");
+    eprintln!("                               e.g. \"%albumartist%/%year% - %album%\"");
 
     std::process::exit(1);
 }
 
-fn handle_cmd_line_flags() {
-    for flag in std::env::args().filter(|x| x.starts_with('-')) {
-        match flag.as_str() {
+/// Reads the command line flags into the global `Config` and returns
+/// the remaining arguments, i.e. the files to be played.
+fn handle_cmd_line_flags() -> Vec<String> {
+    let mut config = config::Config::default();
+    let mut files = Vec::new();
+    let mut patterns = Vec::new();
+    let mut dir_patterns = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
             "--help" => print_usage_and_exit(),
             "--version" => print_version_and_exit(),
-            x => {
+            "--verbose" => config.verbose = true,
+            "--ignore-articles" => config.ignore_articles = true,
+            "--favourites" => config.favourites = true,
+            "--artist" => config.artist = Some(flag_value(&arg, args.next())),
+            "--genre" => config.genre = Some(flag_value(&arg, args.next())),
+            "--composer" => config.composer = Some(flag_value(&arg, args.next())),
+            "--separator" => config.separator = flag_value(&arg, args.next()),
+            "--replaygain" => {
+                config.replaygain = match flag_value(&arg, args.next()).as_str() {
+                    "track" => config::ReplayGain::Track,
+                    "album" => config::ReplayGain::Album,
+                    "off" => config::ReplayGain::Off,
+                    x => invalid_value(&arg, x),
+                }
+            }
+            "--preamp" => config.preamp = gain_value(&arg, args.next()),
+            "--fallback-gain" => config.fallback_gain = gain_value(&arg, args.next()),
+            "--scan-loudness" => config.scan_loudness = true,
+            "--sink" => {
+                let value = flag_value(&arg, args.next());
+                config.sink = match sink::Sink::from_name(&value) {
+                    Some(sink) => Some(sink),
+                    None => invalid_value(&arg, &value),
+                }
+            }
+            "--device" => config.device = Some(flag_value(&arg, args.next())),
+            "--list-devices" => config.list_devices = true,
+            "--crossfade" => {
+                let value = flag_value(&arg, args.next());
+                config.crossfade = match value.parse::<f64>() {
+                    Ok(secs) if secs > 0.0 && secs <= 30.0 => Some(Duration::from_secs_f64(secs)),
+                    _ => invalid_value(&arg, &value),
+                }
+            }
+            "--crossfade-albums" => config.crossfade_albums = true,
+            "--volume" => {
+                let value = flag_value(&arg, args.next());
+                config.volume = match value.parse() {
+                    Ok(n) if n <= 100 => Some(n),
+                    _ => invalid_value(&arg, &value),
+                }
+            }
+            "--sleep" => {
+                let value = flag_value(&arg, args.next());
+                config.sleep = match seek::parse_duration(&value) {
+                    Ok(time) if time > Duration::ZERO => Some(time),
+                    _ => invalid_value(&arg, &value),
+                }
+            }
+            "--seek-step" => config.seek_step = step_value(&arg, args.next()),
+            "--long-seek-step" => config.long_seek_step = step_value(&arg, args.next()),
+            "--pattern" => patterns.push(pattern_value(&arg, args.next())),
+            "--dir-pattern" => dir_patterns.push(pattern_value(&arg, args.next())),
+            "--min-rating" => {
+                let value = flag_value(&arg, args.next());
+                config.min_rating = match value.parse() {
+                    Ok(n) if (1..=ratings::MAX_STARS).contains(&n) => Some(n),
+                    _ => invalid_value(&arg, &value),
+                }
+            }
+            "--various" => config.various_artists.push(flag_value(&arg, args.next())),
+            "--date" => {
+                config.date = match flag_value(&arg, args.next()).as_str() {
+                    "release" => config::DateKind::Release,
+                    "original" => config::DateKind::Original,
+                    x => invalid_value(&arg, x),
+                }
+            }
+            "--artwork" => {
+                config.artwork_names = flag_value(&arg, args.next())
+                    .split(',')
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            }
+            "--cover" => {
+                config.cover = match flag_value(&arg, args.next()).as_str() {
+                    "kitty" => Some(cover::Protocol::Kitty),
+                    "iterm" => Some(cover::Protocol::Iterm),
+                    "sixel" => Some(cover::Protocol::Sixel),
+                    "blocks" => Some(cover::Protocol::Blocks),
+                    "off" => None,
+                    x => invalid_value(&arg, x),
+                }
+            }
+            x if x.starts_with('-') => {
                 eprintln!("Unknown option {}", x);
                 print_usage_and_exit();
             }
+            _ => files.push(arg),
+        }
+    }
+
+    if config.crossfade_albums && config.crossfade.is_none() {
+        eprintln!("Option --crossfade-albums requires --crossfade");
+        print_usage_and_exit();
+    }
+    if config.device.is_some() && config.sink.is_none() {
+        eprintln!("Option --device requires --sink");
+        print_usage_and_exit();
+    }
+    if config.sink == Some(sink::Sink::File) && config.device.is_none() {
+        eprintln!("Option --sink file requires the path of the WAV file in --device");
+        print_usage_and_exit();
+    }
+
+    // Patterns given by the user are tried before the built-in ones
+    config.patterns.splice(0..0, patterns);
+    config.dir_patterns.splice(0..0, dir_patterns);
+    config::set(config);
+    files
+}
+
+/// Prints an error about `value` not being valid for `flag` and exits.
+fn invalid_value(flag: &str, value: &str) -> ! {
+    eprintln!("Invalid value {} for option {}", value, flag);
+    print_usage_and_exit()
+}
+
+/// Returns the gain in decibels given to `flag`, or prints usage and
+/// exits if it is not a number between -60 and 60.
+fn gain_value(flag: &str, value: Option<String>) -> f64 {
+    let value = flag_value(flag, value);
+    match value.parse::<f64>() {
+        Ok(db) if (-60.0..=60.0).contains(&db) => db,
+        _ => invalid_value(flag, &value),
+    }
+}
+
+/// Returns the seek step given to `flag` in seconds, or prints usage
+/// and exits if it is not a number above 0 and at most an hour.
+fn step_value(flag: &str, value: Option<String>) -> Duration {
+    let value = flag_value(flag, value);
+    match value.parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs <= 3600.0 => Duration::from_secs_f64(secs),
+        _ => invalid_value(flag, &value),
+    }
+}
+
+/// Parses the pattern given to `flag`, or prints the error and usage
+/// and exits if it is not valid.
+fn pattern_value(flag: &str, value: Option<String>) -> pattern::Pattern {
+    let value = flag_value(flag, value);
+    match pattern::Pattern::parse(&value) {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            eprintln!("{}", e);
+            invalid_value(flag, &value)
+        }
+    }
+}
+
+/// Returns the value given to `flag`, or prints usage and exits
+/// if there is none.
+fn flag_value(flag: &str, value: Option<String>) -> String {
+    match value {
+        Some(value) if !value.starts_with("--") => value,
+        _ => {
+            eprintln!("Option {} requires a value", flag);
+            print_usage_and_exit()
         }
     }
 }
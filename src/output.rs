@@ -1,14 +1,21 @@
 //! Handles terminal output.
 
-use crate::backend::BackendState;
+use crate::backend::{AbLoop, BackendState, StopAfter};
+use crate::config;
+use crate::cover::{Cover, Protocol, COVER_COLS, COVER_ROWS};
+use crate::equalizer::{self, BANDS, MAX_GAIN, MIN_GAIN};
+use crate::lyrics::Lyrics;
 use crate::playlist::Playlist;
-use crate::song::Song;
-use crate::traits::PrettyDuration;
+use crate::song::{Chapter, Song};
+use crate::traits::{DisplayWidth, PrettyDuration};
 
 use anyhow::Result;
 use itertools::Itertools;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
 use termion::color;
 use termion::raw::{IntoRawMode, RawTerminal};
 
@@ -18,20 +25,60 @@ pub struct Output {
     stdout: RawTerminal<std::io::Stdout>,
     lines_printed: usize, // Number of lines printed on last refresh
     display_help: bool,   // Whether help mode is on
+    display_lyrics: bool, // Whether lyrics mode is on
+
+    /// Whether the equalizer is shown, and the band being adjusted
+    display_equalizer: bool,
+    selected_band: usize,
+
+    /// First line shown of unsynchronized lyrics, and the song they
+    /// belong to
+    lyrics_scroll: (usize, PathBuf),
+
+    /// Songs that could not be played, with the error
+    failed: HashMap<PathBuf, String>,
+    /// Command being typed, or the error from the last one, shown on
+    /// the bottom line
+    prompt: Option<String>,
+
+    /// Covers decoded so far by album, None if the album has none
+    covers: HashMap<String, Option<Cover>>,
+    /// Albums whose cover is being decoded in the background, and the
+    /// channel the decoded covers are sent back through
+    decoding: HashSet<String>,
+    decoded_tx: mpsc::Sender<(String, Option<Cover>)>,
+    decoded_rx: mpsc::Receiver<(String, Option<Cover>)>,
+    /// Album and line of the cover drawn with a graphics protocol
+    cover_drawn: Option<(String, usize)>,
 }
 
 impl Output {
     /// Sets terminal into raw mode and returns a new `Output` struct
     pub fn new() -> Self {
+        let (decoded_tx, decoded_rx) = mpsc::channel();
         Self {
             stdout: stdout().into_raw_mode().expect("Unable to open stdout"),
             lines_printed: 0,
             display_help: false,
+            display_lyrics: false,
+            display_equalizer: false,
+            selected_band: 0,
+            lyrics_scroll: (0, PathBuf::new()),
+            failed: HashMap::new(),
+            prompt: None,
+            covers: HashMap::new(),
+            decoding: HashSet::new(),
+            decoded_tx,
+            decoded_rx,
+            cover_drawn: None,
         }
     }
 
     /// Restores the state of the terminal before quitting
     pub fn cleanup(&self) {
+        if let Some(protocol) = config::get().cover {
+            print!("{}", Cover::clear_sequence(protocol));
+        }
         println!();
         self.stdout.suspend_raw_mode().ok();
     }
@@ -46,6 +93,56 @@ impl Output {
         Ok(())
     }
 
+    /// Switches between the playlist and the lyrics of the current song
+    pub fn toggle_lyrics(&mut self) -> Result<()> {
+        self.display_lyrics = !self.display_lyrics;
+        Ok(())
+    }
+
+    /// Switches between the playlist and the equalizer
+    pub fn toggle_equalizer(&mut self) -> Result<()> {
+        self.display_equalizer = !self.display_equalizer;
+        Ok(())
+    }
+
+    /// Returns true if the equalizer is shown, so that the arrow keys
+    /// adjust it instead of moving in the playlist.
+    pub fn showing_equalizer(&self) -> bool {
+        self.display_equalizer && !self.display_help
+    }
+
+    /// Returns the equalizer band selected for adjusting.
+    pub fn selected_band(&self) -> usize {
+        self.selected_band
+    }
+
+    /// Selects the equalizer band `step` bands up or down.
+    pub fn select_band(&mut self, step: isize) -> Result<()> {
+        let band = self.selected_band as isize + step;
+        self.selected_band = band.clamp(0, BANDS as isize - 1) as usize;
+        Ok(())
+    }
+
+    /// Marks the song at `path` as unplayable, showing `error` under it
+    pub fn mark_failed(&mut self, path: &Path, error: &str) {
+        self.failed.insert(path.to_path_buf(), error.to_string());
+    }
+
+    /// Shows `prompt` on the bottom line, or hides it if None
+    pub fn set_prompt(&mut self, prompt: Option<String>) {
+        self.prompt = prompt;
+    }
+
+    /// Scrolls unsynchronized lyrics by `lines`, up if negative
+    pub fn scroll_lyrics(&mut self, lines: isize) -> Result<()> {
+        let (scroll, _) = &mut self.lyrics_scroll;
+        *scroll = match lines < 0 {
+            true => scroll.saturating_sub(lines.unsigned_abs()),
+            false => scroll.saturating_add(lines.unsigned_abs()),
+        };
+        Ok(())
+    }
+
     /// Refreshes the output printed to the user.
     pub fn refresh(&mut self, state: BackendState, playlist: &Playlist) -> Result<()> {
         // Move cursor back up to where we start printing
@@ -55,11 +152,37 @@ impl Output {
                 .write_all(format!("\x1b[{}A", self.lines_printed).as_ref())?;
         }
 
-        let output = match self.display_help {
-            false => Self::generate_output(state, playlist)?,
-            true => Self::generate_help()?,
+        // Help and lyrics are printed over the cover, so it needs to be
+        // redrawn after
+        if (self.display_help || self.display_lyrics || self.display_equalizer)
+            && self.cover_drawn.take().is_some()
+        {
+            if let Some(protocol) = config::get().cover {
+                self.stdout
+                    .write_all(Cover::clear_sequence(protocol).as_ref())?;
+            }
+        }
+
+        let mut output = match (
+            self.display_help,
+            self.display_equalizer,
+            self.display_lyrics,
+        ) {
+            (true, _, _) => Self::generate_help()?,
+            (false, true, _) => self.generate_equalizer(&state)?,
+            (false, false, true) => self.generate_lyrics(&state, playlist)?,
+            (false, false, false) => self.generate_output(state, playlist)?,
         };
 
+        // The prompt takes the bottom line if the output fills the screen
+        if let Some(prompt) = &self.prompt {
+            let (_, terminal_height) = termion::terminal_size()?;
+            if output.len() >= usize::from(terminal_height) {
+                output.pop();
+            }
+            output.push(format!("{}{}", prompt, termion::clear::AfterCursor));
+        }
+
         self.stdout.write_all(output.join("\r\n").as_ref())?;
         self.stdout.write_all(b"\r")?;
 
@@ -74,16 +197,34 @@ impl Output {
     ///
     /// If the whole playlist does not fit into the terminal, the lines
     /// are printed so that the currently played song is in the middle
-    /// of the window.
-    fn generate_output(state: BackendState, playlist: &Playlist) -> Result<Vec<String>> {
+    /// of the window. The cover of the current album is drawn next to
+    /// its header, see `add_cover()`.
+    fn generate_output(&mut self, state: BackendState, playlist: &Playlist) -> Result<Vec<String>> {
         let mut ret = Vec::new();
         let mut center: usize = 0; // Index of the currently played song
+        let mut header: usize = 0; // Index of the current album's header
 
         let (terminal_height, terminal_width) = {
             let (w, h) = termion::terminal_size()?;
             (usize::try_from(h)?, usize::try_from(w)?)
         };
 
+        // Leave room on the left for the current album's cover
+        let album = playlist.current().map(|s| s.album_info.to_string());
+        let has_cover = match playlist.current() {
+            Some(song) if terminal_width > COVER_COLS + 40 => {
+                self.load_cover(song, playlist.artwork(song))
+            }
+            _ => false,
+        };
+        let width = match has_cover {
+            true => terminal_width - COVER_COLS - 1,
+            false => terminal_width,
+        };
+
+        // The ratings only take room if some song has one to show
+        let rated = playlist.iter().any(|(_, song)| !song.rating.is_empty());
+
         // TODO: This group_by() is being ran every 100ms or so
         // It might be better to store the songs grouped by album
         // in the playlist instead. This would, however, make Playlist
@@ -92,36 +233,314 @@ impl Output {
             .iter()
             .group_by(|(_, song)| song.album_info.to_string())
         {
+            let album_header = ret.len();
             ret.push(format!(
                 "{}{:>width$}{}",
                 termion::style::Underline,
                 album,
                 termion::style::Reset,
-                width = terminal_width,
+                width = width,
             ));
 
             for (playing, song) in songs {
                 if playing {
                     center = ret.len();
-                    ret.push(Self::format_playing_song(song, &state, terminal_width));
+                    header = album_header;
+                    ret.push(Self::format_playing_song(song, &state, rated, width));
+
+                    // Chapters are listed under the song being played
+                    let current = song.current_chapter(state.position);
+                    for (i, chapter) in song.chapters.iter().enumerate() {
+                        if Some(i) == current {
+                            center = ret.len();
+                        }
+                        ret.push(Self::format_chapter(chapter, Some(i) == current, width));
+                    }
                 } else {
-                    ret.push(Self::format_song(song, terminal_width));
+                    ret.push(Self::format_song(song, rated, width));
+                }
+
+                if let Some(error) = self.failed.get(&song.path) {
+                    ret.push(Self::format_error(error, width));
                 }
             }
         }
 
         // Determine which part of the output to print for it to fit
         // the screen and for the currently playing song to be visible
-        if ret.len() <= terminal_height {
-            Ok(ret)
+        let (start_index, end_index) = if ret.len() <= terminal_height {
+            (0, ret.len())
         } else if ret.len() - center < terminal_height / 2 {
-            let start_index = ret.len() - terminal_height;
-            Ok(ret[start_index..].to_vec())
+            (ret.len() - terminal_height, ret.len())
         } else {
             let start_index = center.saturating_sub(terminal_height / 2);
             let end_index = std::cmp::min(ret.len(), start_index + terminal_height);
-            Ok(ret[start_index..end_index].to_vec())
+            (start_index, end_index)
+        };
+        let mut ret = ret[start_index..end_index].to_vec();
+
+        if let (true, Some(album)) = (has_cover, album) {
+            self.add_cover(&mut ret, header.checked_sub(start_index), &album);
         }
+        Ok(ret)
+    }
+
+    /// Starts decoding the cover of `song`'s album in the background,
+    /// unless it has already been decoded. The cover embedded in the
+    /// file is preferred over the `artwork` file found next to it.
+    /// Returns true once the album has a cover to be drawn.
+    fn load_cover(&mut self, song: &Song, artwork: Option<&Path>) -> bool {
+        let protocol = match config::get().cover {
+            Some(protocol) => protocol,
+            None => return false,
+        };
+
+        for (album, cover) in self.decoded_rx.try_iter() {
+            self.decoding.remove(&album);
+            self.covers.insert(album, cover);
+        }
+
+        let album = &song.album_info;
+        if let Some(cover) = self.covers.get(album) {
+            return cover.is_some();
+        }
+        if !song.has_cover && artwork.is_none() {
+            self.covers.insert(album.to_string(), None);
+            return false;
+        }
+        if !self.decoding.insert(album.to_string()) {
+            return false;
+        }
+
+        let (album, path, artwork) = (
+            album.to_string(),
+            song.path.to_path_buf(),
+            artwork.map(Path::to_path_buf),
+        );
+        let has_cover = song.has_cover;
+        let tx = self.decoded_tx.clone();
+        std::thread::spawn(move || {
+            let cover = match artwork {
+                Some(artwork) if !has_cover => Cover::from_file(&artwork, protocol),
+                _ => Cover::from_audio_file(&path, protocol),
+            };
+            if let Err(e) = &cover {
+                glib::g_debug!("output", "{}: {}", album, e);
+            }
+            tx.send((album, cover.ok())).ok(); // Output may be gone
+        });
+        false
+    }
+
+    /// Adds the cover of `album` to the left of `lines`, starting from
+    /// the album header at index `header`, and an empty gutter to the
+    /// other lines. If the header is not visible, the cover is not drawn.
+    ///
+    /// Half blocks are printed as part of the lines. Images drawn with
+    /// a graphics protocol are only sent when the album or its position
+    /// changes, and the cursor is moved over them on other refreshes.
+    fn add_cover(&mut self, lines: &mut [String], header: Option<usize>, album: &str) {
+        let (cover, protocol) = match (self.covers.get(album), config::get().cover) {
+            (Some(Some(cover)), Some(protocol)) => (cover, protocol),
+            _ => return,
+        };
+
+        let blank = " ".repeat(COVER_COLS + 1);
+        let cover_rows = |h: usize| h..h + COVER_ROWS;
+
+        if protocol == Protocol::Blocks {
+            let blocks = cover.blocks();
+            for (i, line) in lines.iter_mut().enumerate() {
+                let gutter = match header {
+                    Some(h) if cover_rows(h).contains(&i) => format!("{} ", blocks[i - h]),
+                    _ => blank.clone(),
+                };
+                line.insert_str(0, &gutter);
+            }
+            return;
+        }
+
+        // The image must fit on the lines printed, or the terminal scrolls
+        let header = header.filter(|h| h + COVER_ROWS <= lines.len());
+        let position = header.map(|h| (album.to_string(), h));
+        let redraw = position != self.cover_drawn;
+        let skip = format!("\x1b[{}C", COVER_COLS + 1);
+
+        for (i, line) in lines.iter_mut().enumerate() {
+            let gutter = match header {
+                Some(h) if redraw && i == h => {
+                    // Save cursor, draw image, restore cursor
+                    format!("\x1b7{}\x1b8{}", cover.escape_sequence(), skip)
+                }
+                Some(h) if cover_rows(h).contains(&i) => skip.clone(),
+                _ => blank.clone(),
+            };
+            line.insert_str(0, &gutter);
+        }
+
+        if redraw {
+            if let Some(first) = lines.first_mut() {
+                first.insert_str(0, Cover::clear_sequence(protocol));
+            }
+        }
+        self.cover_drawn = position;
+    }
+
+    /// Returns the lines showing the lyrics of the current song.
+    ///
+    /// Synchronized lyrics are scrolled so that the line being sung
+    /// is highlighted in the middle of the window. Other lyrics are
+    /// scrolled with `scroll_lyrics()`.
+    fn generate_lyrics(
+        &mut self,
+        state: &BackendState,
+        playlist: &Playlist,
+    ) -> Result<Vec<String>> {
+        let mut ret = Vec::new();
+        let (terminal_height, terminal_width) = {
+            let (w, h) = termion::terminal_size()?;
+            (usize::from(h), usize::from(w))
+        };
+        let empty_line = termion::clear::AfterCursor.to_string();
+
+        let song = match playlist.current() {
+            Some(song) => song,
+            None => return Ok(vec![empty_line]),
+        };
+
+        let header = format!("{}: {}", song.artist, song.title).truncate_to_width(terminal_width);
+        ret.push(format!(
+            "{}{}{}{}",
+            termion::style::Underline,
+            " ".repeat(terminal_width - header.display_width()),
+            header,
+            termion::style::Reset,
+        ));
+
+        // Scroll back to the top when the song changes
+        if self.lyrics_scroll.1 != song.path {
+            self.lyrics_scroll = (0, song.path.to_path_buf());
+        }
+
+        let height = terminal_height.saturating_sub(1);
+        match &song.lyrics {
+            None => ret.push(format!("{:^width$}", "No lyrics", width = terminal_width)),
+            Some(lyrics) => {
+                let lines = lyrics.lines();
+                let current = lyrics.current_line(state.position);
+                let start = match lyrics {
+                    Lyrics::Synced(_) => current.unwrap_or(0).saturating_sub(height / 2),
+                    Lyrics::Unsynced(_) => {
+                        let last = lines.len().saturating_sub(height);
+                        self.lyrics_scroll.0 = std::cmp::min(self.lyrics_scroll.0, last);
+                        self.lyrics_scroll.0
+                    }
+                };
+
+                for (i, line) in lines.iter().enumerate().skip(start).take(height) {
+                    let color = match Some(i) == current {
+                        true => color::Fg(color::LightWhite).to_string(),
+                        false => color::Fg(color::White).to_string(),
+                    };
+                    ret.push(format!(
+                        "{}{}{}",
+                        color,
+                        Self::center(line, terminal_width),
+                        color::Fg(color::Reset),
+                    ));
+                }
+            }
+        }
+
+        while ret.len() < terminal_height {
+            ret.push(empty_line.clone());
+        }
+
+        Ok(ret)
+    }
+
+    /// Returns `text` cut to `width` terminal columns and centered in
+    /// them. Wide characters such as CJK take two columns each.
+    fn center(text: &str, width: usize) -> String {
+        let text = text.truncate_to_width(width);
+        let margin = width - text.display_width();
+        format!(
+            "{}{}{}",
+            " ".repeat(margin / 2),
+            text,
+            " ".repeat(margin - margin / 2)
+        )
+    }
+
+    /// Returns the lines showing the equalizer: the preset and a slider
+    /// for each band, with the selected band highlighted.
+    fn generate_equalizer(&self, state: &BackendState) -> Result<Vec<String>> {
+        let mut ret = Vec::new();
+        let terminal_height = usize::from(termion::terminal_size()?.1);
+        let empty_line = termion::clear::AfterCursor.to_string();
+
+        ret.push(empty_line.clone());
+        let settings = match &state.equalizer {
+            Some(settings) => settings,
+            None => {
+                ret.push(format!("Equalizer not available{}", empty_line));
+                ret.resize(terminal_height, empty_line);
+                return Ok(ret);
+            }
+        };
+
+        let modified = match settings.modified {
+            true => " (adjusted)",
+            false => "",
+        };
+        ret.push(format!(
+            "Equalizer: {}{}{}",
+            settings.preset, modified, empty_line
+        ));
+        ret.push(empty_line.clone());
+
+        for (i, gain) in settings.gains.iter().enumerate() {
+            let (color, marker) = match i == self.selected_band {
+                true => (color::Fg(color::LightWhite).to_string(), "›"),
+                false => (color::Fg(color::White).to_string(), " "),
+            };
+            ret.push(format!(
+                "{}{} {:>7} {} {:+5.1} dB{}{}",
+                color,
+                marker,
+                equalizer::FREQUENCIES[i],
+                Self::format_slider(*gain),
+                gain,
+                color::Fg(color::Reset),
+                empty_line
+            ));
+        }
+
+        ret.push(empty_line.clone());
+        ret.push(format!(
+            "up/down: choose band  left/right: adjust  p: next preset  e: close{}",
+            empty_line
+        ));
+
+        while ret.len() < terminal_height {
+            ret.push(empty_line.clone());
+        }
+
+        Ok(ret)
+    }
+
+    /// Returns a slider with one position for each dB from `MIN_GAIN`
+    /// to `MAX_GAIN`, marked at `gain`.
+    fn format_slider(gain: f64) -> String {
+        let knob = (gain - MIN_GAIN).round() as usize;
+        let zero = (-MIN_GAIN) as usize;
+        (0..=(MAX_GAIN - MIN_GAIN) as usize)
+            .map(|i| match i {
+                i if i == knob => '●',
+                i if i == zero => '┼',
+                _ => '─',
+            })
+            .collect()
     }
 
     /// FIXME
@@ -158,6 +577,86 @@ impl Output {
             termion::clear::AfterCursor
         ));
 
+        ret.push(format!(
+            " < and >           seek further backwards/forward{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " 0 to 9            seek to 0% to 90%{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " home and end      seek to the start/end{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " :seek 3:25, +1m   seek to a position or by a step{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " + and -, * and /  raise/lower volume{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " }} and {{           faster/slower, backspace: normal speed{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " ] and [           next/previous chapter{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " a and b           loop from point A to B, A: stop looping{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " s                 sleep timer: 15, 30, 60 minutes or off{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " t and T           stop after this song/album{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " r and R           raise/lower rating{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " f                 mark as favourite{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " e                 show equalizer{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " p                 next equalizer preset{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " L                 show lyrics{}",
+            termion::clear::AfterCursor
+        ));
+
+        ret.push(format!(
+            " page up/down      scroll lyrics{}",
+            termion::clear::AfterCursor
+        ));
+
         while ret.len() < terminal_height {
             ret.push(empty_line.clone());
         }
@@ -166,42 +665,130 @@ impl Output {
     }
 
     /// Returns the line of output to be printed
-    /// for the currently playing song.
-    fn format_playing_song(song: &Song, state: &BackendState, terminal_width: usize) -> String {
+    /// for the currently playing song, with its rating if `rated`.
+    fn format_playing_song(
+        song: &Song,
+        state: &BackendState,
+        rated: bool,
+        terminal_width: usize,
+    ) -> String {
         let icon = match (state.playing, state.muted) {
             (true, true) => "🔇",
             (true, false) => " ▶",
             (false, _) => " ⏸",
         };
 
-        let time = format!("{}/{}", state.position.pretty(), song.duration.pretty());
+        // The volume and ReplayGain adjustment are shown next to the
+        // time, and the playback rate when it is not the normal one,
+        // and the A–B loop, sleep timer and stop after the song or
+        // album if they are on
+        let gain = match state.replaygain {
+            Some(db) => format!("{:+.1} dB  ", db),
+            None => String::new(),
+        };
+        let rate = match (state.rate - 1.0).abs() < f64::EPSILON {
+            true => String::new(),
+            false => format!("{:.1}x  ", state.rate),
+        };
+        let ab_loop = match state.ab_loop {
+            Some(AbLoop { a, b: Some(b) }) => format!("loop {}-{}  ", a.pretty(), b.pretty()),
+            Some(AbLoop { a, b: None }) => format!("loop {}-  ", a.pretty()),
+            None => String::new(),
+        };
+        let sleep = match state.sleep {
+            Some(left) => format!("sleep {}  ", left.pretty()),
+            None => String::new(),
+        };
+        let stop_after = match state.stop_after {
+            Some(StopAfter::Song) => "stop after song  ",
+            Some(StopAfter::Album) => "stop after album  ",
+            None => "",
+        };
+        let time = format!(
+            "{}{}{}{}{:>3}%  {}{}/{}",
+            stop_after,
+            sleep,
+            ab_loop,
+            rate,
+            state.volume,
+            gain,
+            state.position.pretty(),
+            song.duration.pretty()
+        );
 
+        let rating = Self::format_rating(song, rated);
         format!(
-            "{} {}{:>3} {:width$} {:>time_width$}{}",
+            "{} {}{:>3} {:width$}{} {:>time_width$}{}",
             color::Fg(color::LightWhite),
             icon,
             song.track_number,
             song,
+            rating,
             time,
             color::Fg(color::Reset),
-            width = terminal_width - 9 - time.len(),
+            width = terminal_width.saturating_sub(9 + rating.display_width() + time.len()),
             time_width = time.len() + 1
         )
     }
 
+    /// Returns the line of output to be printed under a song that
+    /// could not be played, indented like chapters.
+    fn format_error(error: &str, terminal_width: usize) -> String {
+        format!(
+            "{}{:>8} {}{}",
+            color::Fg(color::Red),
+            "✗",
+            error.truncate_to_width(terminal_width.saturating_sub(9)),
+            color::Fg(color::Reset),
+        )
+    }
+
+    /// Returns the line of output to be printed for a chapter of the
+    /// song being played, indented under the song.
+    fn format_chapter(chapter: &Chapter, current: bool, terminal_width: usize) -> String {
+        let (color, marker) = match current {
+            true => (color::Fg(color::LightWhite).to_string(), "›"),
+            false => (color::Fg(color::White).to_string(), ""),
+        };
+        let start = chapter.start.pretty();
+        let width = terminal_width - 11 - start.len();
+
+        format!(
+            "{}{:>8} {:width$.width$} {:>time_width$}{}",
+            color,
+            marker,
+            chapter.title,
+            start,
+            color::Fg(color::Reset),
+            width = width,
+            time_width = start.len() + 1
+        )
+    }
+
     /// Returns the line of output to be printed for a song that is not
-    /// being played.
-    fn format_song(song: &Song, terminal_width: usize) -> String {
+    /// being played, with its rating if `rated`.
+    fn format_song(song: &Song, rated: bool, terminal_width: usize) -> String {
         let duration = song.duration.pretty();
+        let rating = Self::format_rating(song, rated);
         format!(
-            "{}{:>6} {:width$} {:>time_width$}{}",
+            "{}{:>6} {:width$}{} {:>time_width$}{}",
             color::Fg(color::White),
             song.track_number,
             song,
+            rating,
             duration,
             color::Fg(color::Reset),
-            width = terminal_width - 9 - duration.len(),
+            width = terminal_width.saturating_sub(9 + rating.display_width() + duration.len()),
             time_width = duration.len() + 1
         )
     }
+
+    /// Returns the rating column of `song`, empty unless `rated`, i.e.
+    /// some song in the playlist has a rating to show.
+    fn format_rating(song: &Song, rated: bool) -> String {
+        match rated {
+            true => format!(" {}", song.rating),
+            false => String::new(),
+        }
+    }
 }
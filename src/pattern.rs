@@ -0,0 +1,278 @@
+//! Patterns for reading metadata from the names of untagged files,
+//! e.g. "%artist% - %album% - %track% - %title%", and of the directories
+//! they are in, e.g. "%albumartist%/%year% - %album%".
+
+use anyhow::{anyhow, Result};
+
+/// Patterns tried when the user has not given any, in order. The first
+/// one matching the file name is used.
+pub const DEFAULT_PATTERNS: [&str; 6] = [
+    "%artist% - %album% - %track% - %title%",
+    "%artist% - %track% - %title%",
+    "%track% - %title%",
+    "%track%. %title%",
+    "%track% %title%",
+    "%artist% - %title%",
+];
+
+/// Patterns for the directories of untagged files tried when the user
+/// has not given any. Each "/" stands for a directory level, counted
+/// from the one the file is in.
+pub const DEFAULT_DIR_PATTERNS: [&str; 5] = [
+    "%albumartist%/%year% - %album%",
+    "%albumartist%/%album% (%year%)",
+    "%albumartist% - %year% - %album%",
+    "%albumartist% - %album% (%year%)",
+    "%albumartist% - %album%",
+];
+
+/// A field of metadata a pattern can contain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Artist,
+    AlbumArtist,
+    Album,
+    Track,
+    Title,
+    Year,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// A file name pattern, parsed from text where `%artist%`,
+/// `%albumartist%`, `%album%`, `%track%`, `%title%` and `%year%` stand
+/// for the metadata and anything else must be matched as it is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pattern {
+    parts: Vec<Part>,
+}
+
+/// Metadata read from a file name. Fields not in the pattern are None.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PatternFields {
+    pub artist: Option<String>,
+    pub album_artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+    pub title: Option<String>,
+    pub year: Option<i32>,
+}
+
+impl Pattern {
+    /// Parses `pattern`. Fields must be separated by some text, or
+    /// there would be no telling where one ends and the next begins.
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = pattern;
+
+        while let Some(start) = rest.find('%') {
+            let end = rest[start + 1..]
+                .find('%')
+                .ok_or_else(|| anyhow!("Unterminated field in {:?}", pattern))?;
+            let name = &rest[start + 1..start + 1 + end];
+            let field = match name {
+                "artist" => Field::Artist,
+                "albumartist" => Field::AlbumArtist,
+                "album" => Field::Album,
+                "track" => Field::Track,
+                "title" => Field::Title,
+                "year" => Field::Year,
+                _ => return Err(anyhow!("Unknown field %{}% in {:?}", name, pattern)),
+            };
+
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            } else if let Some(Part::Field(_)) = parts.last() {
+                return Err(anyhow!("Fields must be separated in {:?}", pattern));
+            }
+            parts.push(Part::Field(field));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        match parts.iter().any(|p| matches!(p, Part::Field(_))) {
+            true => Ok(Self { parts }),
+            false => Err(anyhow!("No fields in {:?}", pattern)),
+        }
+    }
+
+    /// Matches `text`, e.g. a file name without the extension, against
+    /// the pattern. Returns None if it does not match.
+    pub fn fields(&self, text: &str) -> Option<PatternFields> {
+        let mut values = Vec::new();
+        match Self::match_parts(&self.parts, text, &mut values) {
+            true => {
+                let mut ret = PatternFields::default();
+                for (field, value) in values {
+                    let value = value.trim().to_string();
+                    match field {
+                        Field::Artist => ret.artist = Some(value),
+                        Field::AlbumArtist => ret.album_artist = Some(value),
+                        Field::Album => ret.album = Some(value),
+                        Field::Track => ret.track = value.parse().ok(),
+                        Field::Title => ret.title = Some(value),
+                        Field::Year => ret.year = value.parse().ok(),
+                    }
+                }
+                Some(ret)
+            }
+            false => None,
+        }
+    }
+
+    /// Matches `parts` against `text`, trying every place where the
+    /// text after a field could start. The matched values are added
+    /// to `values`.
+    fn match_parts<'a>(parts: &[Part], text: &'a str, values: &mut Vec<(Field, &'a str)>) -> bool {
+        match parts {
+            [] => text.is_empty(),
+            [Part::Literal(literal), rest @ ..] => match text.strip_prefix(literal.as_str()) {
+                Some(text) => Self::match_parts(rest, text, values),
+                None => false,
+            },
+            [Part::Field(field), Part::Literal(literal), rest @ ..] => {
+                for (i, _) in text.match_indices(literal.as_str()) {
+                    let value = &text[..i];
+                    if Self::valid(*field, value) {
+                        values.push((*field, value));
+                        if Self::match_parts(rest, &text[i + literal.len()..], values) {
+                            return true;
+                        }
+                        values.pop();
+                    }
+                }
+                false
+            }
+            [Part::Field(field), ..] => {
+                let valid = Self::valid(*field, text);
+                if valid {
+                    values.push((*field, text));
+                }
+                valid
+            }
+        }
+    }
+
+    /// Returns the number of path components the pattern matches,
+    /// i.e. one more than the number of "/" in it.
+    pub fn depth(&self) -> usize {
+        let separators: usize = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.matches('/').count(),
+                Part::Field(_) => 0,
+            })
+            .sum();
+        separators + 1
+    }
+
+    /// Returns true if `value` can be the value of `field`: track
+    /// numbers have one to three digits, years four, and other fields
+    /// must not be empty nor span several directories.
+    fn valid(field: Field, value: &str) -> bool {
+        let digits = |n: std::ops::RangeInclusive<usize>| {
+            n.contains(&value.len()) && value.chars().all(|c| c.is_ascii_digit())
+        };
+
+        match field {
+            Field::Track => digits(1..=3),
+            Field::Year => digits(4..=4),
+            _ => !value.trim().is_empty() && !value.contains('/'),
+        }
+    }
+}
+
+/// Returns the fields read from `name` with the first of `patterns`
+/// that matches it.
+pub fn first_match(patterns: &[Pattern], name: &str) -> Option<PatternFields> {
+    patterns.iter().find_map(|p| p.fields(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> Vec<Pattern> {
+        DEFAULT_PATTERNS
+            .iter()
+            .map(|p| Pattern::parse(p).unwrap())
+            .collect()
+    }
+
+    #[test]
+    // The most common layouts are recognized by the built-in patterns
+    fn default_patterns_matched() {
+        let fields = first_match(&defaults(), "Artist - Album - 03 - Title").unwrap();
+        assert_eq!(fields.artist.as_deref(), Some("Artist"));
+        assert_eq!(fields.album.as_deref(), Some("Album"));
+        assert_eq!(fields.track, Some(3));
+        assert_eq!(fields.title.as_deref(), Some("Title"));
+
+        let fields = first_match(&defaults(), "1. Song 1").unwrap();
+        assert_eq!(
+            (fields.track, fields.title.as_deref()),
+            (Some(1), Some("Song 1"))
+        );
+
+        let fields = first_match(&defaults(), "07 Let It Be - Remastered").unwrap();
+        assert_eq!(fields.track, Some(7));
+        assert_eq!(fields.title.as_deref(), Some("Let It Be - Remastered"));
+
+        let fields = first_match(&defaults(), "Nico - These Days").unwrap();
+        assert_eq!(fields.artist.as_deref(), Some("Nico"));
+        assert_eq!(fields.track, None);
+
+        assert_eq!(first_match(&defaults(), "Untitled"), None);
+    }
+
+    #[test]
+    // Separators may also appear within the values, as long as the
+    // rest of the pattern still matches
+    fn separators_within_values() {
+        let pattern = Pattern::parse("%artist% - %year% - %album% - %track% - %title%").unwrap();
+        let fields = pattern
+            .fields("Bob Marley - The Wailers - 1973 - Catch a Fire - 01 - Concrete Jungle")
+            .unwrap();
+        assert_eq!(fields.artist.as_deref(), Some("Bob Marley - The Wailers"));
+        assert_eq!(fields.year, Some(1973));
+        assert_eq!(fields.title.as_deref(), Some("Concrete Jungle"));
+    }
+
+    #[test]
+    // Directory patterns match the last directories of the path
+    fn directory_patterns_matched() {
+        let patterns: Vec<Pattern> = DEFAULT_DIR_PATTERNS
+            .iter()
+            .map(|p| Pattern::parse(p).unwrap())
+            .collect();
+        assert_eq!(patterns[0].depth(), 2);
+        assert_eq!(patterns[2].depth(), 1);
+
+        let fields = first_match(&patterns, "Nick Drake/1972 - Pink Moon").unwrap();
+        assert_eq!(fields.album_artist.as_deref(), Some("Nick Drake"));
+        assert_eq!(fields.album.as_deref(), Some("Pink Moon"));
+        assert_eq!(fields.year, Some(1972));
+
+        let fields = first_match(&patterns, "Nick Drake/Pink Moon (1972)").unwrap();
+        assert_eq!(fields.album.as_deref(), Some("Pink Moon"));
+        assert_eq!(fields.year, Some(1972));
+
+        // Values never span directories
+        assert_eq!(patterns[4].fields("Music/Nick Drake - Pink Moon"), None);
+    }
+
+    #[test]
+    fn invalid_patterns_rejected() {
+        assert!(Pattern::parse("%artist%%title%").is_err());
+        assert!(Pattern::parse("%artist% - %name%").is_err());
+        assert!(Pattern::parse("%artist - %title%").is_err());
+        assert!(Pattern::parse("no fields").is_err());
+    }
+}
@@ -1,27 +1,45 @@
 //! Keeps track of the contents of and position in the playlist.
 
-use crate::song::Song;
+use crate::config;
+use crate::ratings::{Ratings, MAX_STARS};
+use crate::song::{Song, SongError};
 use crate::traits::PathContents;
 
 use glib::ThreadPool;
 use itertools::{enumerate, Itertools};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 
 /// Number of worker threads to use for reading song metadata
 const N_WORKERS: u32 = 8;
 
+/// Extensions of image files searched for album artwork
+const ARTWORK_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "webp", "gif", "bmp"];
+
+/// Extensions of files commonly found next to audio files, which
+/// are not even tried to be read as songs
+const IGNORED_EXTENSIONS: [&str; 20] = [
+    "accurip", "bmp", "cue", "db", "gif", "ini", "jpeg", "jpg", "log", "lrc", "m3u", "m3u8", "md5",
+    "nfo", "pdf", "pls", "png", "sfv", "txt", "webp",
+];
+
 #[derive(Clone)]
 /// Keeps track of the contents of and position in the playlist.
 pub struct Playlist {
     store: Vec<Song>,
     currently_playing: usize,
+    /// Artwork found next to the audio files, by album
+    artwork: HashMap<String, PathBuf>,
+    /// Ratings and favourites stored by soi
+    ratings: Ratings,
 }
 
 impl Playlist {
     /// Converts the pathnames in `files` into `Song`s and returns them
     /// as a `Playlist`. If one of `files` cannot be opened as an audio
-    /// stream, it is quietly ignored and not added to the playlist.
+    /// stream, it is not added to the playlist. The rejected files are
+    /// summarized after the scan, see `report_skipped()`.
     ///
     /// Each song is created in a new thread. from() returns when every
     /// thread has finished.
@@ -35,11 +53,13 @@ impl Playlist {
         for (i, path) in enumerate(files)
             .map(|(i, f)| std::iter::repeat(i).zip(f.contents()))
             .flatten()
+            .filter(|(_, path)| !Self::ignored(path))
         {
             let thread_tx = tx.clone();
             pool.push(move || {
+                let song = Song::from(path.to_path_buf());
                 thread_tx
-                    .send((i, Song::from(path)))
+                    .send((i, path, song))
                     .expect("Failed to send Song to Playlist");
             })
             .expect("Failed to push thread to pool");
@@ -51,23 +71,162 @@ impl Playlist {
 
         // Sort Songs returned from worker threads based on
         //   1. The original order (i.e. order of command line arguments)
-        //   2. Based on the album
+        //   2. Based on the album, see Song::album_sort_key()
         //   3. Based on the track number
-        let store: Vec<Song> = rx
-            .iter()
-            .filter_map(|(i, song)| Some((i, song?)))
-            .sorted_by_key(|(i, song)| (*i, song.album_info.to_string(), song.track_number))
+        let (mut songs, skipped): (Vec<_>, Vec<_>) =
+            rx.iter().partition_map(|(i, path, song)| match song {
+                Ok(song) => itertools::Either::Left((i, song)),
+                Err(e) => itertools::Either::Right((path, e)),
+            });
+        Self::detect_compilations(&mut songs);
+        Self::report_skipped(skipped);
+
+        // Stored ratings replace the ones read from the tags
+        let ratings = Ratings::load();
+        for (_, song) in songs.iter_mut() {
+            if let Some(rating) = ratings.get(&song.path, song.content_hash.as_deref()) {
+                song.rating = rating;
+            }
+        }
+
+        let store: Vec<Song> = songs
+            .into_iter()
+            .filter(|(_, song)| song.passes_filters())
+            .sorted_by_key(|(i, song)| (*i, song.album_sort_key(), song.track_number))
             .map(|(_i, song)| song)
             .collect();
 
         if store.is_empty() {
-            eprintln!("No playable files provided\n");
+            match config::get().has_filters() {
+                true => eprintln!("No songs match the filters\n"),
+                false => eprintln!("No playable files provided\n"),
+            }
             crate::print_usage_and_exit();
         }
 
+        let artwork = Self::find_artwork(&store);
+
         Self {
             store,
             currently_playing: 0,
+            artwork,
+            ratings,
+        }
+    }
+
+    /// Looks for artwork such as cover.jpg or folder.png in the
+    /// directories of the songs, see `Config::artwork_names`.
+    /// Each directory is searched only once.
+    fn find_artwork(songs: &[Song]) -> HashMap<String, PathBuf> {
+        let mut by_directory: HashMap<PathBuf, Option<PathBuf>> = HashMap::new();
+        let mut ret = HashMap::new();
+
+        for song in songs {
+            let dir = match song.path.parent() {
+                Some(dir) => dir,
+                None => continue,
+            };
+            let artwork = by_directory
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| Self::artwork_in(dir));
+
+            if let Some(artwork) = artwork {
+                ret.entry(song.album_info.to_string())
+                    .or_insert_with(|| artwork.to_path_buf());
+            }
+        }
+
+        ret
+    }
+
+    /// Returns the image in `dir` whose name (without the extension)
+    /// comes first in `Config::artwork_names`, ignoring case.
+    fn artwork_in(dir: &Path) -> Option<PathBuf> {
+        let images: Vec<(String, PathBuf)> = dir
+            .read_dir()
+            .ok()?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+                extension.is_some_and(|e| ARTWORK_EXTENSIONS.contains(&e.as_str()))
+            })
+            .filter_map(|path| {
+                let stem = path.file_stem()?.to_string_lossy().to_lowercase();
+                Some((stem, path))
+            })
+            .sorted()
+            .collect();
+
+        config::get().artwork_names.iter().find_map(|name| {
+            images
+                .iter()
+                .find(|(stem, _)| *stem == name.to_lowercase())
+                .map(|(_, path)| path.to_path_buf())
+        })
+    }
+
+    /// Returns the path of the artwork found next to the audio files
+    /// of `song`'s album, if there is any. Use `PathToURI::to_uri()`
+    /// for publishing it as an art URL.
+    pub fn artwork(&self, song: &Song) -> Option<&Path> {
+        self.artwork.get(&song.album_info).map(PathBuf::as_path)
+    }
+
+    /// Returns true if `path` is a file commonly found next to audio
+    /// files that is not worth reporting as skipped.
+    fn ignored(path: &Path) -> bool {
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        extension.is_some_and(|e| IGNORED_EXTENSIONS.contains(&e.as_str()))
+    }
+
+    /// Prints the number of files that could not be added to the
+    /// playlist. With `--verbose`, every file is listed along with
+    /// the reason.
+    fn report_skipped(mut skipped: Vec<(PathBuf, SongError)>) {
+        if skipped.is_empty() {
+            return;
+        }
+
+        if config::get().verbose {
+            skipped.sort_by(|a, b| a.0.cmp(&b.0));
+            for (path, error) in &skipped {
+                eprintln!("Skipped {}: {}", path.display(), error);
+            }
+        }
+
+        match skipped.len() {
+            1 => eprintln!("Skipped 1 unplayable file"),
+            n => eprintln!("Skipped {} unplayable files", n),
+        }
+        if !config::get().verbose {
+            eprintln!("Run with --verbose for details");
+        }
+    }
+
+    /// Marks songs as part of a compilation when their album has no
+    /// album artist and the album's tracks have differing artists.
+    /// Tracks are considered to be on the same album when they have
+    /// the same album title and are in the same directory.
+    fn detect_compilations(songs: &mut [(usize, Song)]) {
+        let key = |song: &Song| {
+            (
+                song.path.parent().map(Path::to_path_buf),
+                song.album_title.to_string(),
+            )
+        };
+
+        let mut artists: HashMap<_, HashSet<String>> = HashMap::new();
+        for (_, song) in songs.iter().filter(|(_, s)| !s.has_album_artist()) {
+            artists
+                .entry(key(song))
+                .or_default()
+                .insert(song.artist.to_string());
+        }
+
+        for (_, song) in songs.iter_mut() {
+            if artists.get(&key(song)).map_or(0, |a| a.len()) > 1 {
+                song.set_compilation();
+            }
         }
     }
 
@@ -101,6 +260,38 @@ impl Playlist {
         self.store.get(self.currently_playing + 1)
     }
 
+    /// Raises the rating of the current song by `stars`, or lowers it
+    /// if negative. Lowering a one-star rating removes it.
+    pub fn rate_current(&mut self, stars: i8) -> anyhow::Result<()> {
+        if let Some(song) = self.store.get_mut(self.currently_playing) {
+            let current = song.rating.stars.unwrap_or(0) as i8;
+            let new = (current + stars).clamp(0, MAX_STARS as i8) as u8;
+            song.rating.stars = Some(new).filter(|n| *n > 0);
+        }
+        self.save_current_rating()
+    }
+
+    /// Marks the current song as a favourite, or unmarks it.
+    pub fn toggle_favourite(&mut self) -> anyhow::Result<()> {
+        if let Some(song) = self.store.get_mut(self.currently_playing) {
+            song.rating.favourite = !song.rating.favourite;
+        }
+        self.save_current_rating()
+    }
+
+    /// Writes the rating of the current song to the ratings database.
+    /// A failure to write it is logged rather than interrupting
+    /// playback.
+    fn save_current_rating(&mut self) -> anyhow::Result<()> {
+        if let Some(song) = self.store.get(self.currently_playing) {
+            let hash = song.content_hash.as_deref();
+            if let Err(e) = self.ratings.set(&song.path, hash, song.rating) {
+                glib::g_warning!("playlist", "Unable to save rating: {:#}", e);
+            }
+        }
+        Ok(())
+    }
+
     /// Returns an iterator over the songs with a boolean indicating
     /// whether the song is currently being played.
     pub fn iter(&self) -> impl Iterator<Item = (bool, &Song)> {
@@ -128,6 +319,22 @@ mod tests {
         paths.iter().map(|p| p.canonicalize().unwrap()).collect()
     }
 
+    #[test]
+    // Artwork is found by the configured names, ignoring case, and
+    // the first name on the list is preferred
+    fn artwork_found_case_insensitively() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("soi-artwork-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        for file in &["Folder.JPG", "Cover.Png", "back.jpg", "cover.txt"] {
+            std::fs::write(dir.join(file), b"")?;
+        }
+
+        let artwork = Playlist::artwork_in(&dir);
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(artwork, Some(dir.join("Cover.Png")));
+        Ok(())
+    }
+
     #[test]
     // soi 1.mp3 2.mp3 3.mp3 etc. should be opened in that order
     fn command_line_arguments_handled_in_order() -> Result<()> {
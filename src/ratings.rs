@@ -0,0 +1,266 @@
+//! Ratings and favourites, stored in a file of their own so that
+//! the audio files are never modified.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Highest rating a song can have
+pub const MAX_STARS: u8 = 5;
+
+/// Number of bytes read from the start and the end of a file for
+/// its content hash
+const HASHED_BYTES: u64 = 64 * 1024;
+
+/// Rating of a single song.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rating {
+    /// 1 to `MAX_STARS`, None if the song has not been rated
+    pub stars: Option<u8>,
+    pub favourite: bool,
+}
+
+impl Rating {
+    /// Converts a rating given in percent, e.g. by an ID3 POPM frame,
+    /// to stars. 0 means the song has not been rated.
+    pub fn stars_from_percent(percent: u32) -> Option<u8> {
+        match percent {
+            0 => None,
+            p => Some(std::cmp::min(p, 100).div_ceil(20) as u8),
+        }
+    }
+
+    /// Converts a rating between 0.0 and 1.0, e.g. in an FMPS_Rating
+    /// tag, to stars. 0.0 means the song has not been rated.
+    pub fn stars_from_fraction(fraction: f64) -> Option<u8> {
+        let stars = (fraction.clamp(0.0, 1.0) * f64::from(MAX_STARS)).round() as u8;
+        match stars {
+            0 if fraction > 0.0 => Some(1),
+            0 => None,
+            n => Some(n),
+        }
+    }
+
+    /// Returns true if there is nothing worth storing or showing.
+    pub fn is_empty(&self) -> bool {
+        self.stars.is_none() && !self.favourite
+    }
+}
+
+impl std::fmt::Display for Rating {
+    /// Shows the rating as a heart for favourites followed by five
+    /// stars, or blanks for songs that have not been rated.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let heart = match self.favourite {
+            true => '♥',
+            false => ' ',
+        };
+        let stars: String = match self.stars {
+            Some(n) => (0..MAX_STARS)
+                .map(|i| if i < n { '★' } else { '☆' })
+                .collect(),
+            None => " ".repeat(usize::from(MAX_STARS)),
+        };
+        write!(f, "{}{}", heart, stars)
+    }
+}
+
+/// Stored ratings, keyed by content hash with the path as a fallback
+/// for files whose contents have changed, e.g. after retagging.
+/// A file that has been moved or renamed is found by its hash.
+#[derive(Clone, Default)]
+pub struct Ratings {
+    /// Path of the database file
+    file: PathBuf,
+    by_hash: HashMap<String, (PathBuf, Rating)>,
+    by_path: HashMap<PathBuf, String>,
+}
+
+impl Ratings {
+    /// Reads the ratings from `$XDG_DATA_HOME/soi/ratings`. If the file
+    /// does not exist yet, no song has been rated.
+    pub fn load() -> Self {
+        let file = glib::user_data_dir().join("soi").join("ratings");
+        let text = std::fs::read_to_string(&file).unwrap_or_default();
+        Self::parse(file, &text)
+    }
+
+    /// Parses the database in `text`, with one song per line:
+    /// hash, stars (0 if not rated), favourite (0 or 1) and path,
+    /// separated by tabs.
+    fn parse(file: PathBuf, text: &str) -> Self {
+        let mut ratings = Self {
+            file,
+            ..Self::default()
+        };
+
+        for line in text.lines() {
+            let fields: Vec<&str> = line.splitn(4, '\t').collect();
+            if let [hash, stars, favourite, path] = fields[..] {
+                let rating = Rating {
+                    stars: stars.parse().ok().filter(|n| (1..=MAX_STARS).contains(n)),
+                    favourite: favourite == "1",
+                };
+                ratings.insert(hash, Path::new(path), rating);
+            }
+        }
+
+        ratings
+    }
+
+    /// Returns the stored rating of the file at `path` with the given
+    /// content hash, if there is one.
+    pub fn get(&self, path: &Path, hash: Option<&str>) -> Option<Rating> {
+        let hash = hash
+            .filter(|h| self.by_hash.contains_key(*h))
+            .or_else(|| self.by_path.get(path).map(String::as_str))?;
+        self.by_hash.get(hash).map(|(_, rating)| *rating)
+    }
+
+    /// Stores `rating` for the file at `path` and writes the database.
+    /// Files without a hash, e.g. unreadable ones, are keyed by path.
+    pub fn set(&mut self, path: &Path, hash: Option<&str>, rating: Rating) -> Result<()> {
+        let path_key = format!("path:{}", path.display());
+        let hash = hash.unwrap_or(&path_key).to_string();
+        self.insert(&hash, path, rating);
+        self.save()
+    }
+
+    /// Replaces any entries for `path` or `hash` with `rating`.
+    fn insert(&mut self, hash: &str, path: &Path, rating: Rating) {
+        if let Some(old_hash) = self.by_path.remove(path) {
+            self.by_hash.remove(&old_hash);
+        }
+        if let Some((old_path, _)) = self.by_hash.remove(hash) {
+            self.by_path.remove(&old_path);
+        }
+
+        if !rating.is_empty() {
+            self.by_hash
+                .insert(hash.to_string(), (path.to_path_buf(), rating));
+            self.by_path.insert(path.to_path_buf(), hash.to_string());
+        }
+    }
+
+    /// Returns the contents of the database file, sorted by path.
+    fn serialize(&self) -> String {
+        let mut entries: Vec<_> = self.by_hash.iter().collect();
+        entries.sort_by(|a, b| (a.1).0.cmp(&(b.1).0));
+
+        entries
+            .into_iter()
+            .map(|(hash, (path, rating))| {
+                format!(
+                    "{}\t{}\t{}\t{}\n",
+                    hash,
+                    rating.stars.unwrap_or(0),
+                    u8::from(rating.favourite),
+                    path.display()
+                )
+            })
+            .collect()
+    }
+
+    /// Writes the database, replacing the file only once it has been
+    /// written completely.
+    fn save(&self) -> Result<()> {
+        let dir = self
+            .file
+            .parent()
+            .context("Ratings file has no directory")?;
+        std::fs::create_dir_all(dir).with_context(|| format!("{:?}", dir))?;
+
+        let tmp = self.file.with_extension("tmp");
+        std::fs::write(&tmp, self.serialize()).with_context(|| format!("{:?}", tmp))?;
+        std::fs::rename(&tmp, &self.file).with_context(|| format!("{:?}", self.file))?;
+        Ok(())
+    }
+}
+
+/// Returns a hash of the size and the first and last 64 KiB of the file
+/// at `path`, which is enough to recognize it after it has been moved
+/// without reading all of it.
+pub fn content_hash(path: &Path) -> Option<String> {
+    let mut file = std::fs::File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+
+    let mut checksum = glib::Checksum::new(glib::ChecksumType::Sha1)?;
+    checksum.update(&size.to_le_bytes());
+
+    let mut buffer = Vec::new();
+    (&mut file)
+        .take(HASHED_BYTES)
+        .read_to_end(&mut buffer)
+        .ok()?;
+    if size > HASHED_BYTES {
+        file.seek(SeekFrom::Start(size.saturating_sub(HASHED_BYTES)))
+            .ok()?;
+        file.take(HASHED_BYTES).read_to_end(&mut buffer).ok()?;
+    }
+    checksum.update(&buffer);
+
+    checksum.string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // POPM ratings are in percent, FMPS ratings between 0.0 and 1.0
+    fn tag_ratings_converted_to_stars() {
+        assert_eq!(Rating::stars_from_percent(0), None);
+        assert_eq!(Rating::stars_from_percent(1), Some(1));
+        assert_eq!(Rating::stars_from_percent(60), Some(3));
+        assert_eq!(Rating::stars_from_percent(61), Some(4));
+        assert_eq!(Rating::stars_from_percent(100), Some(5));
+
+        assert_eq!(Rating::stars_from_fraction(0.0), None);
+        assert_eq!(Rating::stars_from_fraction(0.05), Some(1));
+        assert_eq!(Rating::stars_from_fraction(0.8), Some(4));
+        assert_eq!(Rating::stars_from_fraction(1.5), Some(5));
+    }
+
+    #[test]
+    // A moved file is found by its hash, a retagged one by its path
+    fn ratings_found_by_hash_or_path() {
+        let file = PathBuf::from("ratings");
+        let mut ratings = Ratings::parse(file.clone(), "");
+        let rating = Rating {
+            stars: Some(4),
+            favourite: true,
+        };
+        ratings.insert("abc", Path::new("/music/a.flac"), rating);
+
+        assert_eq!(
+            ratings.get(Path::new("/moved/a.flac"), Some("abc")),
+            Some(rating)
+        );
+        assert_eq!(
+            ratings.get(Path::new("/music/a.flac"), Some("def")),
+            Some(rating)
+        );
+        assert_eq!(ratings.get(Path::new("/music/b.flac"), Some("def")), None);
+
+        // Entries survive being written and read back
+        let ratings = Ratings::parse(file, &ratings.serialize());
+        assert_eq!(ratings.get(Path::new("/music/a.flac"), None), Some(rating));
+    }
+
+    #[test]
+    // Rating a song again replaces the old entry, and removing the
+    // rating removes the entry altogether
+    fn ratings_replaced() {
+        let mut ratings = Ratings::parse(PathBuf::from("ratings"), "abc\t2\t0\t/music/a.flac\n");
+        let rating = Rating {
+            stars: Some(5),
+            favourite: false,
+        };
+        ratings.insert("def", Path::new("/music/a.flac"), rating);
+        assert_eq!(ratings.serialize(), "def\t5\t0\t/music/a.flac\n");
+
+        ratings.insert("def", Path::new("/music/a.flac"), Rating::default());
+        assert_eq!(ratings.serialize(), "");
+    }
+}
@@ -0,0 +1,97 @@
+//! Release dates of albums, as precise as the tags allow.
+
+use glib::translate::IntoGlib;
+
+/// A date with an optional month and day, e.g. "1969" or "1969-09-26".
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ReleaseDate {
+    pub year: i32,
+    pub month: Option<u32>,
+    pub day: Option<u32>,
+}
+
+impl ReleaseDate {
+    /// Parses dates in the forms "YYYY", "YYYY-MM" and "YYYY-MM-DD",
+    /// ignoring anything after the day (e.g. a time). Returns None if
+    /// the string does not start with a year.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.trim().splitn(3, '-');
+        let year = parts.next()?.get(..4)?.parse().ok()?;
+        let month = parts
+            .next()
+            .and_then(|m| m.get(..2)?.parse().ok())
+            .filter(|m| (1..=12).contains(m));
+        let day = parts
+            .next()
+            .filter(|_| month.is_some())
+            .and_then(|d| d.get(..2)?.parse().ok())
+            .filter(|d| (1..=31).contains(d));
+
+        Some(Self { year, month, day })
+    }
+}
+
+impl From<&gst::DateTime> for ReleaseDate {
+    fn from(date: &gst::DateTime) -> Self {
+        Self {
+            year: date.year(),
+            month: date.month().map(|m| m as u32),
+            day: date.day().map(|d| d as u32),
+        }
+    }
+}
+
+impl From<&glib::Date> for ReleaseDate {
+    fn from(date: &glib::Date) -> Self {
+        Self {
+            year: i32::from(date.year()),
+            month: Some(date.month().into_glib() as u32),
+            day: Some(u32::from(date.day())),
+        }
+    }
+}
+
+impl std::fmt::Display for ReleaseDate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.month, self.day) {
+            (Some(month), Some(day)) => write!(f, "{}-{:02}-{:02}", self.year, month, day),
+            (Some(month), None) => write!(f, "{}-{:02}", self.year, month),
+            _ => write!(f, "{}", self.year),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Dates in tags come with varying precision
+    fn dates_parsed() {
+        let date = ReleaseDate::parse("1969-09-26").unwrap();
+        assert_eq!((date.year, date.month, date.day), (1969, Some(9), Some(26)));
+        assert_eq!(date.to_string(), "1969-09-26");
+
+        let date = ReleaseDate::parse("1969-09").unwrap();
+        assert_eq!((date.year, date.month, date.day), (1969, Some(9), None));
+
+        let date = ReleaseDate::parse("1969").unwrap();
+        assert_eq!((date.year, date.month, date.day), (1969, None, None));
+
+        let date = ReleaseDate::parse("2001-05-14T00:00:00Z").unwrap();
+        assert_eq!(date.to_string(), "2001-05-14");
+
+        assert!(ReleaseDate::parse("19").is_none());
+        assert!(ReleaseDate::parse("unknown").is_none());
+    }
+
+    #[test]
+    // Less precise dates sort before more precise ones of the same year
+    fn dates_ordered() {
+        let year = ReleaseDate::parse("1969").unwrap();
+        let month = ReleaseDate::parse("1969-01").unwrap();
+        let day = ReleaseDate::parse("1969-01-01").unwrap();
+        let later = ReleaseDate::parse("1970").unwrap();
+        assert!(year < month && month < day && day < later);
+    }
+}
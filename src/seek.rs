@@ -0,0 +1,186 @@
+//! Positions in a song the user can jump to.
+
+use anyhow::{anyhow, Context, Result};
+use std::time::{Duration, Instant};
+
+/// How far before the end of the song `Seek::End` jumps to
+const END_MARGIN: Duration = Duration::from_secs(10);
+
+/// Key repeats closer together than this count as holding the key down
+const REPEAT_WINDOW: Duration = Duration::from_millis(600);
+/// Number of key repeats after which a held key seeks by one more step
+const REPEATS_PER_STEP: u32 = 5;
+/// Largest number of steps a held key seeks by at once
+const MAX_STEPS: u32 = 8;
+
+/// A jump within the song being played.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Seek {
+    /// To a position from the start of the song
+    To(Duration),
+    Forward(Duration),
+    Backward(Duration),
+    /// To a percentage of the song's duration
+    Percent(u8),
+    /// To shortly before the end of the song
+    End,
+}
+
+impl Seek {
+    /// Parses the argument of the `:seek` command: a position such as
+    /// "3:25", "1:02:03" or "90", a step such as "+1m", "-30s" or
+    /// "+1m30s", or a percentage such as "50%".
+    pub fn parse(arg: &str) -> Result<Self> {
+        let arg = arg.trim();
+        if let Some(percent) = arg.strip_suffix('%') {
+            return match percent.trim().parse() {
+                Ok(p) if p <= 100 => Ok(Self::Percent(p)),
+                _ => Err(anyhow!("Invalid percentage {}", arg)),
+            };
+        }
+
+        match (arg.strip_prefix('+'), arg.strip_prefix('-')) {
+            (Some(step), _) => Ok(Self::Forward(parse_duration(step)?)),
+            (_, Some(step)) => Ok(Self::Backward(parse_duration(step)?)),
+            _ => Ok(Self::To(parse_duration(arg)?)),
+        }
+    }
+
+    /// Returns the position to seek to from `position` in a song that
+    /// is `duration` long, never past its end.
+    pub fn target(self, position: Duration, duration: Duration) -> Duration {
+        let target = match self {
+            Self::To(t) => t,
+            Self::Forward(step) => position.saturating_add(step),
+            Self::Backward(step) => position.saturating_sub(step),
+            Self::Percent(p) => duration.mul_f64(f64::from(p) / 100.0),
+            Self::End => duration.saturating_sub(END_MARGIN),
+        };
+        target.min(duration)
+    }
+}
+
+/// Grows the seek step while a seek key is held down.
+#[derive(Debug, Default)]
+pub struct Acceleration {
+    /// Step, direction and time of the last seek
+    last: Option<(Duration, bool, Instant)>,
+    /// Number of times the last seek has been repeated in a row
+    repeats: u32,
+}
+
+impl Acceleration {
+    /// Returns how far to seek at `now` for a key seeking by `step`,
+    /// forward if `forward` is true. The step is multiplied the longer
+    /// the same key keeps repeating.
+    pub fn step(&mut self, step: Duration, forward: bool, now: Instant) -> Duration {
+        self.repeats = match self.last {
+            Some((s, f, t))
+                if (s, f) == (step, forward)
+                    && now.saturating_duration_since(t) < REPEAT_WINDOW =>
+            {
+                self.repeats + 1
+            }
+            _ => 0,
+        };
+        self.last = Some((step, forward, now));
+        step * (1 + self.repeats / REPEATS_PER_STEP).min(MAX_STEPS)
+    }
+}
+
+/// Parses a time given as "[[h:]m:]s" or with units, e.g. "1h2m3s",
+/// "1m" or "30s". Seconds may have a fractional part.
+pub fn parse_duration(text: &str) -> Result<Duration> {
+    let invalid = || anyhow!("Invalid time {}", text);
+    let seconds = |s: &str| -> Result<f64> {
+        match s.parse::<f64>() {
+            Ok(s) if s.is_finite() && s >= 0.0 => Ok(s),
+            _ => Err(invalid()),
+        }
+    };
+
+    let mut total = 0.0;
+    if text.contains(':') {
+        for part in text.split(':') {
+            total = total * 60.0 + seconds(part)?;
+        }
+    } else if text.ends_with(|c: char| c.is_ascii_alphabetic()) {
+        let mut rest = text;
+        while !rest.is_empty() {
+            let unit = rest
+                .find(|c: char| c.is_ascii_alphabetic())
+                .context("Time is missing a unit")?;
+            let factor = match &rest[unit..=unit] {
+                "h" => 3600.0,
+                "m" => 60.0,
+                "s" => 1.0,
+                _ => return Err(invalid()),
+            };
+            total += seconds(&rest[..unit])? * factor;
+            rest = &rest[unit + 1..];
+        }
+    } else {
+        total = seconds(text)?;
+    }
+
+    Ok(Duration::from_secs_f64(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Positions, steps and percentages are told apart, in each of the
+    // ways of writing a time
+    fn seek_parsed() {
+        let secs = Duration::from_secs;
+        assert_eq!(Seek::parse("3:25").unwrap(), Seek::To(secs(205)));
+        assert_eq!(Seek::parse("1:02:03").unwrap(), Seek::To(secs(3723)));
+        assert_eq!(Seek::parse("90").unwrap(), Seek::To(secs(90)));
+        assert_eq!(Seek::parse("+1m").unwrap(), Seek::Forward(secs(60)));
+        assert_eq!(Seek::parse("-30s").unwrap(), Seek::Backward(secs(30)));
+        assert_eq!(Seek::parse("+1m30s").unwrap(), Seek::Forward(secs(90)));
+        assert_eq!(Seek::parse("50%").unwrap(), Seek::Percent(50));
+
+        assert!(Seek::parse("").is_err());
+        assert!(Seek::parse("3:xx").is_err());
+        assert!(Seek::parse("+1x").is_err());
+        assert!(Seek::parse("150%").is_err());
+    }
+
+    #[test]
+    // Targets stay within the song
+    fn target_clamped() {
+        let secs = Duration::from_secs;
+        let (position, duration) = (secs(60), secs(200));
+        assert_eq!(Seek::To(secs(300)).target(position, duration), duration);
+        assert_eq!(Seek::Forward(secs(30)).target(position, duration), secs(90));
+        assert_eq!(Seek::Backward(secs(90)).target(position, duration), secs(0));
+        assert_eq!(Seek::Percent(30).target(position, duration), secs(60));
+        assert_eq!(Seek::End.target(position, duration), secs(190));
+        assert_eq!(Seek::End.target(position, secs(5)), secs(0));
+    }
+
+    #[test]
+    // Steps grow while the same key repeats, up to a limit, and start
+    // over after a pause or when another key is pressed
+    fn step_accelerated() {
+        let step = Duration::from_secs(5);
+        let mut acceleration = Acceleration::default();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let steps: Vec<_> = (0..12)
+            .map(|i| acceleration.step(step, true, at(i * 30)).as_secs())
+            .collect();
+        assert_eq!(steps, [5, 5, 5, 5, 5, 10, 10, 10, 10, 10, 15, 15]);
+
+        assert_eq!(acceleration.step(step, false, at(400)), step);
+        assert_eq!(acceleration.step(step, false, at(2000)), step);
+        for i in 0..100 {
+            acceleration.step(step, false, at(2000 + i * 30));
+        }
+        assert_eq!(acceleration.step(step, false, at(5000)), step * MAX_STEPS);
+    }
+}
@@ -0,0 +1,127 @@
+//! Chooses the audio output: a sound server, a device or a WAV file.
+
+use anyhow::{anyhow, Context, Result};
+use gst::prelude::*;
+
+/// Audio outputs that can be chosen instead of `autoaudiosink`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sink {
+    Pulse,
+    Pipewire,
+    Alsa,
+    Jack,
+    /// Decoded audio written to a WAV file, e.g. for testing on
+    /// machines without a sound card
+    File,
+}
+
+impl Sink {
+    /// Returns the sink named on the command line, e.g. "pulse".
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pulse" => Some(Self::Pulse),
+            "pipewire" => Some(Self::Pipewire),
+            "alsa" => Some(Self::Alsa),
+            "jack" => Some(Self::Jack),
+            "file" => Some(Self::File),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the sink on the command line.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Pulse => "pulse",
+            Self::Pipewire => "pipewire",
+            Self::Alsa => "alsa",
+            Self::Jack => "jack",
+            Self::File => "file",
+        }
+    }
+
+    /// Returns the GStreamer element factory of the sink.
+    fn factory(self) -> &'static str {
+        match self {
+            Self::Pulse => "pulsesink",
+            Self::Pipewire => "pipewiresink",
+            Self::Alsa => "alsasink",
+            Self::Jack => "jackaudiosink",
+            Self::File => "filesink",
+        }
+    }
+
+    /// Returns the sink whose element factory is `factory`.
+    fn from_factory(factory: &str) -> Option<Self> {
+        [Self::Pulse, Self::Pipewire, Self::Alsa, Self::Jack]
+            .iter()
+            .copied()
+            .find(|sink| sink.factory() == factory)
+    }
+}
+
+/// Properties the sinks take the device in, in order of preference:
+/// `pipewiresink` has replaced `path` with `target-object`, and
+/// `jackaudiosink` connects to the ports matching `port-pattern`.
+const DEVICE_PROPERTIES: [&str; 4] = ["device", "target-object", "path", "port-pattern"];
+
+/// Creates the element to be used as playbin's `audio-sink`. `device`
+/// is the output device, or the path of the WAV file for `Sink::File`.
+pub fn make(sink: Sink, device: Option<&str>) -> Result<gst::Element> {
+    if sink == Sink::File {
+        let path = device.context("No path given for the WAV file")?;
+        let bin = gst::parse_bin_from_description(
+            "audioconvert ! wavenc ! filesink name=filesink sync=true",
+            true,
+        )?;
+        let filesink = bin.by_name("filesink").context("filesink")?;
+        filesink.set_property("location", path)?;
+        return Ok(bin.upcast());
+    }
+
+    let element = gst::ElementFactory::make(sink.factory(), None)
+        .with_context(|| format!("Unable to create the `{}` element", sink.factory()))?;
+    if let Some(device) = device {
+        let property = DEVICE_PROPERTIES
+            .iter()
+            .find(|p| element.has_property(**p, None))
+            .ok_or_else(|| anyhow!("The {} sink does not take a device", sink.name()))?;
+        element.set_property(*property, device)?;
+    }
+    Ok(element)
+}
+
+/// Prints the audio output devices found by GStreamer, with the sink
+/// and device to give on the command line to use each of them.
+pub fn list_devices() -> Result<()> {
+    let monitor = gst::DeviceMonitor::new();
+    monitor
+        .add_filter(Some("Audio/Sink"), None)
+        .context("Unable to monitor audio devices")?;
+    monitor.start()?;
+
+    for device in monitor.devices() {
+        let element = match device.create_element(None) {
+            Ok(element) => element,
+            Err(_) => continue,
+        };
+        let sink = element
+            .factory()
+            .and_then(|f| Sink::from_factory(f.name().as_str()))
+            .map_or("?", Sink::name);
+        let name = DEVICE_PROPERTIES
+            .iter()
+            .filter(|p| element.has_property(**p, None))
+            .find_map(|p| element.property(*p).ok()?.get::<Option<String>>().ok()?)
+            .unwrap_or_default();
+
+        println!(
+            "--sink {:<8} --device {:<40}  {}",
+            sink,
+            name,
+            device.display_name()
+        );
+    }
+
+    monitor.stop();
+    Ok(())
+}
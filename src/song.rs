@@ -1,9 +1,18 @@
 //! A single audio track on the playlist.
 
-use crate::traits::{AudioPlaybin, PathToURI};
+use crate::config::{self, DateKind};
+use crate::cover;
+use crate::lyrics::Lyrics;
+use crate::pattern::{self, PatternFields};
+use crate::ratings::{self, Rating};
+use crate::release_date::ReleaseDate;
+use crate::traits::{AudioPlaybin, DisplayWidth, PathToURI};
 
 use anyhow::Result;
+use glib::CollationKey;
 use gst::prelude::*;
+use itertools::Itertools;
+use std::convert::TryInto;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
@@ -20,29 +29,183 @@ pub struct Song {
     pub album_title: String,
     pub album_info: String, // "Artist: Title (Year)"
 
+    // Names used for sorting, e.g. "Beatles, The"
+    pub album_artist_sortname: String,
+    pub album_sortname: String,
+    pub artist_sortname: String,
+
     pub artist: String,
     pub title: String,
+
+    // Every value of tags that may have several, e.g. a track
+    // credited to two artists. `artist` and `album_artist` are
+    // these joined with `Config::separator`.
+    pub artists: Vec<String>,
+    pub album_artists: Vec<String>,
+    pub genres: Vec<String>,
+    pub composers: Vec<String>,
     pub track_number: u32,
-    year: Option<i32>,
+
+    /// Release date of this edition of the album
+    pub date: Option<ReleaseDate>,
+    /// Date the album was first released, e.g. before a reissue
+    pub original_date: Option<ReleaseDate>,
+
+    /// Whether the album is a compilation of different artists,
+    /// see `part_of_compilation()`
+    compilation: bool,
+    /// Whether the album artist was read from the tags. One read from
+    /// the directory names of an untagged file does not count.
+    has_album_artist: bool,
 
     pub duration: Duration,
+
+    /// Whether an image is embedded in the file. It is only read when
+    /// the album's cover is drawn, see `Cover::from_audio_file()`.
+    pub has_cover: bool,
+
+    /// Lyrics from an .lrc file next to the audio file or the tags
+    pub lyrics: Option<Lyrics>,
+
+    /// Chapters of audiobooks and long mixes, in order
+    pub chapters: Vec<Chapter>,
+
+    /// Rating read from the tags, replaced by the stored one if the
+    /// song has been rated in soi, see `Playlist::from()`
+    pub rating: Rating,
+    /// Hash identifying the file after it has been moved,
+    /// see `ratings::content_hash()`
+    pub content_hash: Option<String>,
+    /// True if the file has ReplayGain tags, so that its loudness
+    /// need not be analyzed, see `loudness::pending()`
+    pub replaygain_tagged: bool,
+}
+
+/// A chapter within a song, read from the table of contents.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chapter {
+    pub title: String,
+    pub start: Duration,
+}
+
+impl Chapter {
+    /// Returns the chapters in `toc`, also those nested in editions,
+    /// ordered by their start time.
+    fn from_toc(toc: &gst::Toc) -> Vec<Self> {
+        fn collect(entries: Vec<gst::TocEntry>, ret: &mut Vec<(Duration, Option<String>)>) {
+            for entry in entries {
+                let start = entry.start_stop_times().map(|(start, _)| start);
+                if let (gst::TocEntryType::Chapter, Some(start)) = (entry.entry_type(), start) {
+                    let title = entry.tags().and_then(|tags| {
+                        tags.get::<gst::tags::Title>().map(|t| t.get().to_string())
+                    });
+                    ret.push((Duration::from_nanos(start.max(0) as u64), title));
+                }
+                collect(entry.sub_entries(), ret);
+            }
+        }
+
+        let mut chapters = Vec::new();
+        collect(toc.entries(), &mut chapters);
+        chapters.sort_by_key(|(start, _)| *start);
+
+        // Untitled chapters are numbered
+        chapters
+            .into_iter()
+            .enumerate()
+            .map(|(i, (start, title))| Self {
+                title: title.unwrap_or_else(|| format!("Chapter {}", i + 1)),
+                start,
+            })
+            .collect()
+    }
+}
+
+/// How long to wait for GStreamer while reading a file before giving up
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reason for a file not being added to the playlist.
+#[derive(Debug)]
+pub enum SongError {
+    /// Not an audio file GStreamer recognizes
+    UnsupportedFormat,
+    /// An audio file needing a GStreamer plugin that is not installed
+    MissingCodec(String),
+    /// An audio file that could not be decoded
+    Corrupt(String),
+    /// Reading the duration and tags took longer than `SCAN_TIMEOUT`
+    Timeout,
+    /// The file could not be opened for reading
+    PermissionDenied,
+    /// Any other error, e.g. failing to set up the pipeline
+    Other(String),
+}
+
+impl std::fmt::Display for SongError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedFormat => write!(f, "unsupported format"),
+            Self::MissingCodec(e) => write!(f, "missing codec ({})", e),
+            Self::Corrupt(e) => write!(f, "corrupt file ({})", e),
+            Self::Timeout => write!(f, "timed out"),
+            Self::PermissionDenied => write!(f, "permission denied"),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SongError {}
+
+impl From<&glib::Error> for SongError {
+    /// Classifies an error posted by GStreamer while reading a file.
+    fn from(e: &glib::Error) -> Self {
+        if let Some(kind) = e.kind::<gst::StreamError>() {
+            match kind {
+                gst::StreamError::TypeNotFound | gst::StreamError::WrongType => {
+                    Self::UnsupportedFormat
+                }
+                gst::StreamError::CodecNotFound => Self::MissingCodec(e.to_string()),
+                _ => Self::Corrupt(e.to_string()),
+            }
+        } else if let Some(kind) = e.kind::<gst::ResourceError>() {
+            match kind {
+                gst::ResourceError::NotAuthorized => Self::PermissionDenied,
+                _ => Self::Other(e.to_string()),
+            }
+        } else {
+            Self::Other(e.to_string())
+        }
+    }
 }
 
 impl Song {
-    /// Creates a new `Song` from the provided `PathBuf`
-    pub fn from(path: PathBuf) -> Option<Self> {
-        let playbin = Self::setup_pipeline().ok()?;
+    /// Creates a new `Song` from the provided `PathBuf`. Returns a
+    /// `SongError` describing why, if the file cannot be played.
+    pub fn from(path: PathBuf) -> std::result::Result<Self, SongError> {
+        if let Err(e) = std::fs::File::open(&path) {
+            return Err(match e.kind() {
+                std::io::ErrorKind::PermissionDenied => SongError::PermissionDenied,
+                _ => SongError::Other(e.to_string()),
+            });
+        }
+
+        let playbin = Self::setup_pipeline().map_err(|e| SongError::Other(e.to_string()))?;
 
-        let (duration, tags) = Self::get_track_info(&path, playbin)?;
+        let (duration, tags, chapters) = Self::get_track_info(&path, playbin)?;
 
         let mut song = Self {
+            content_hash: ratings::content_hash(&path),
             path,
             duration,
+            chapters,
             ..Self::default()
         };
         song.read_metadata(&tags);
 
-        Some(song)
+        let lyrics_tag = tags.get::<gst::tags::Lyrics>();
+        song.lyrics = Lyrics::load(&song.path, lyrics_tag.as_ref().map(|v| v.get()));
+
+        Ok(song)
     }
 
     /// Creates and sets up the GStreamer pipeline to verify
@@ -62,11 +225,21 @@ impl Song {
     }
 
     /// Decodes the audio file until we have the duration and tags
-    /// read. On error, returns None.
-    fn get_track_info(path: &Path, playbin: gst::Element) -> Option<(Duration, gst::TagList)> {
+    /// read, and the table of contents if the file has one. On error,
+    /// returns the reason the file could not be read.
+    fn get_track_info(
+        path: &Path,
+        playbin: gst::Element,
+    ) -> std::result::Result<(Duration, gst::TagList, Vec<Chapter>), SongError> {
         let mut duration = None;
         let mut tags = None;
+        let mut chapters = Vec::new();
+        let mut prerolled = false;
+        let mut error = None;
 
+        let bus = playbin
+            .bus()
+            .ok_or_else(|| SongError::Other("no message bus".to_string()))?;
         playbin
             .set_property("uri", path.to_uri())
             .expect("Unable to set pipeline URI");
@@ -74,18 +247,36 @@ impl Song {
             .set_state(gst::State::Playing)
             .expect("Unable to set the pipeline to the `Playing` state");
 
-        // Decode file until the tags and the duration are read.
+        // Decode file until it has prerolled and the duration is read.
         // In case of an error (not an audio file or a corrupt one),
-        // stop and return None.
-        for msg in playbin.bus()?.iter_timed(gst::ClockTime::NONE) {
+        // stop and return the error.
+        let deadline = std::time::Instant::now() + SCAN_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            let msg =
+                match bus.timed_pop(gst::ClockTime::from_nseconds(remaining.as_nanos() as u64)) {
+                    Some(msg) => msg,
+                    None => {
+                        error = Some(SongError::Timeout);
+                        break;
+                    }
+                };
+
             match msg.view() {
                 gst::MessageView::Tag(msg) => {
                     tags = Some(msg.tags());
                 }
                 gst::MessageView::Error(e) => {
                     glib::g_debug!("song", "{:?}: {}", path, e.error());
+                    error = Some(SongError::from(&e.error()));
                     break;
                 }
+                gst::MessageView::Toc(msg) => {
+                    chapters = Chapter::from_toc(&msg.toc().0);
+                }
+                // Demuxers post the table of contents before prerolling
+                gst::MessageView::AsyncDone(_) => prerolled = true,
+                gst::MessageView::Eos(_) => break,
                 _ => (),
             }
 
@@ -97,7 +288,9 @@ impl Song {
                     .map(|ct| ct.into());
             }
 
-            if duration.and(tags.as_ref()).is_some() {
+            // Tags are posted before the pipeline has prerolled, so an
+            // untagged file need not be decoded to the end
+            if prerolled && duration.is_some() {
                 break;
             }
         }
@@ -107,51 +300,425 @@ impl Song {
             .set_state(gst::State::Null)
             .expect("Unable to set the pipeline to the `Null` state");
 
-        Some((duration?, tags?))
+        if let Some(error) = error {
+            return Err(error);
+        }
+
+        // Files without any tags are fine, as long as they can be played
+        match duration {
+            Some(duration) => Ok((duration, tags.unwrap_or_else(gst::TagList::new), chapters)),
+            None => Err(SongError::Corrupt("unknown duration".to_string())),
+        }
     }
 
     /// Populates the `Song`s metadata information from
     /// the provided `TagList`.
     fn read_metadata(&mut self, tags: &gst::TagList) {
-        self.album_title = match tags.get::<gst::tags::Album>() {
-            Some(album) => album.get().to_string(),
-            None => "Unknown album".to_string(),
+        // Missing tags are filled in from the file name, if it matches
+        // one of the patterns
+        let from_name = self
+            .path
+            .file_stem()
+            .and_then(|stem| pattern::first_match(&config::get().patterns, &stem.to_string_lossy()))
+            .unwrap_or_default();
+        let from_dirs = self.fields_from_directories();
+
+        self.album_title = match (tags.get::<gst::tags::Album>(), from_name.album) {
+            (Some(album), _) => album.get().to_string(),
+            (None, Some(album)) => album,
+            (None, None) => from_dirs
+                .album
+                .unwrap_or_else(|| "Unknown album".to_string()),
         };
 
-        self.artist = match tags.get::<gst::tags::Artist>() {
-            Some(artist) => artist.get().to_string(),
-            None => "Unknown artist".to_string(),
+        let separator = &config::get().separator;
+        self.album_artists = Self::all_values::<gst::tags::AlbumArtist>(tags);
+        self.has_album_artist = !self.album_artists.is_empty();
+        // Directory names are only used for untagged files, as most
+        // tagged files have no album artist tag
+        let untagged =
+            tags.get::<gst::tags::Album>().is_none() && tags.get::<gst::tags::Artist>().is_none();
+        if self.album_artists.is_empty() && untagged {
+            self.album_artists.extend(from_dirs.album_artist);
+        }
+        self.artists = Self::all_values::<gst::tags::Artist>(tags);
+        if self.artists.is_empty() {
+            let artist = from_name
+                .artist
+                .or_else(|| self.album_artists.first().cloned());
+            self.artists.extend(artist);
+        }
+        self.genres = Self::all_values::<gst::tags::Genre>(tags);
+        self.composers = Self::all_values::<gst::tags::Composer>(tags);
+
+        self.artist = match self.artists.is_empty() {
+            true => "Unknown artist".to_string(),
+            false => self.artists.join(separator),
         };
 
-        self.album_artist = match tags.get::<gst::tags::AlbumArtist>() {
-            Some(artist) => artist.get().to_string(),
-            None => self.artist.to_string(),
+        self.album_artist = match self.album_artists.is_empty() {
+            false => self.album_artists.join(separator),
+            true => self.artist.to_string(),
         };
 
         // If title is not found, fallback to basename
-        self.title = match tags.get::<gst::tags::Title>() {
-            Some(title) => title.get().to_string(),
-            None => format!("{:?}", self.path.file_stem().unwrap_or_default())
+        self.title = match (tags.get::<gst::tags::Title>(), from_name.title) {
+            (Some(title), _) => title.get().to_string(),
+            (None, Some(title)) => title,
+            (None, None) => format!("{:?}", self.path.file_stem().unwrap_or_default())
                 .trim_matches('"')
                 .to_string(),
         };
 
+        self.artist_sortname = match tags.get::<gst::tags::ArtistSortname>() {
+            Some(name) => name.get().to_string(),
+            None => Self::sortname(&self.artist),
+        };
+
+        // Without an album artist, the album is sorted by the track artist
+        self.album_artist_sortname = match tags.get::<gst::tags::AlbumArtistSortname>() {
+            Some(name) => name.get().to_string(),
+            None if self.album_artists.is_empty() => self.artist_sortname.to_string(),
+            None => Self::sortname(&self.album_artist),
+        };
+
+        self.album_sortname = match tags.get::<gst::tags::AlbumSortname>() {
+            Some(name) => name.get().to_string(),
+            None => Self::sortname(&self.album_title),
+        };
+
         self.track_number = tags
             .get::<gst::tags::TrackNumber>()
             .map(|v| v.get())
+            .or(from_name.track)
             .unwrap_or_default();
 
-        self.year = tags.get::<gst::tags::DateTime>().map(|v| v.get().year());
+        // Some formats only have the plain GDate tag, e.g. Vorbis comments
+        self.date = match tags.get::<gst::tags::DateTime>() {
+            Some(date) => Some(ReleaseDate::from(&date.get())),
+            None => tags
+                .get::<gst::tags::Date>()
+                .map(|date| ReleaseDate::from(&date.get())),
+        };
+        if self.date.is_none() {
+            self.date = from_name.year.or(from_dirs.year).map(|year| ReleaseDate {
+                year,
+                month: None,
+                day: None,
+            });
+        }
+
+        self.original_date = Self::original_date(tags);
+
+        self.has_cover = cover::embedded_image(tags).is_some();
+
+        self.rating.stars = Self::rating_tag(tags);
+
+        self.replaygain_tagged = tags.get::<gst::tags::TrackGain>().is_some()
+            || tags.get::<gst::tags::AlbumGain>().is_some();
+
+        self.compilation = Self::compilation_flag(tags)
+            || (!self.album_artists.is_empty()
+                && config::get().is_various_artists(&self.album_artist));
+
+        self.update_album_info();
+    }
+
+    /// Returns the album artist, album and year read from the names of
+    /// the directories the file is in, with the first of
+    /// `Config::dir_patterns` that matches. If none matches, the name of
+    /// the directory is used as the album title, so that untagged albums
+    /// in different directories are kept apart.
+    fn fields_from_directories(&self) -> PatternFields {
+        let dirs: Vec<String> = self
+            .path
+            .parent()
+            .into_iter()
+            .flat_map(Path::components)
+            .filter_map(|c| match c {
+                std::path::Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                _ => None,
+            })
+            .collect();
+
+        let matched = config::get().dir_patterns.iter().find_map(|pattern| {
+            let start = dirs.len().checked_sub(pattern.depth())?;
+            pattern.fields(&dirs[start..].join("/"))
+        });
+
+        matched.unwrap_or_else(|| PatternFields {
+            album: dirs.last().cloned(),
+            ..PatternFields::default()
+        })
+    }
+
+    /// Returns every non-empty value of the tag `T`, without duplicates,
+    /// in the order they are in the file.
+    fn all_values<'a, T: gst::Tag<'a, TagType = &'a str> + 'a>(
+        tags: &'a gst::TagList,
+    ) -> Vec<String> {
+        tags.iter_tag::<T>()
+            .map(|v| v.get().trim().to_string())
+            .filter(|v| !v.is_empty())
+            .unique()
+            .collect()
+    }
+
+    /// Returns true if any of the artists or album artists contains
+    /// `name`, ignoring case.
+    pub fn has_artist(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.artists
+            .iter()
+            .chain(self.album_artists.iter())
+            .any(|artist| artist.to_lowercase().contains(&name))
+    }
+
+    /// Returns true if the song passes the `--min-rating`,
+    /// `--favourites`, `--artist`, `--genre` and `--composer` filters.
+    pub fn passes_filters(&self) -> bool {
+        let config = config::get();
+        let stars = self.rating.stars.unwrap_or(0);
+        config.min_rating.is_none_or(|min| stars >= min)
+            && (self.rating.favourite || !config.favourites)
+            && config.artist.as_ref().is_none_or(|a| self.has_artist(a))
+            && config.genre.as_ref().is_none_or(|g| self.has_genre(g))
+            && config
+                .composer
+                .as_ref()
+                .is_none_or(|c| self.has_composer(c))
+    }
+
+    /// Returns true if any of the genres contains `genre`, ignoring case.
+    pub fn has_genre(&self, genre: &str) -> bool {
+        let genre = genre.to_lowercase();
+        self.genres
+            .iter()
+            .any(|g| g.to_lowercase().contains(&genre))
+    }
 
-        self.album_info = match self.year {
-            Some(year) => format!("{}: {} ({})", self.album_artist, self.album_title, year),
+    /// Returns true if any of the composers contains `name`, ignoring
+    /// case.
+    pub fn has_composer(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.composers
+            .iter()
+            .any(|composer| composer.to_lowercase().contains(&name))
+    }
+
+    /// Returns the date shown and sorted by, as chosen with
+    /// `Config::date`. The original release date falls back to the
+    /// release date of this edition if it is not tagged.
+    pub fn album_date(&self) -> Option<ReleaseDate> {
+        match config::get().date {
+            DateKind::Release => self.date,
+            DateKind::Original => self.original_date.or(self.date),
+        }
+    }
+
+    /// Sets `album_info` based on the album artist, title and year.
+    fn update_album_info(&mut self) {
+        self.album_info = match self.album_date() {
+            Some(date) => format!(
+                "{}: {} ({})",
+                self.album_artist, self.album_title, date.year
+            ),
             None => format!("{}: {}", self.album_artist, self.album_title),
         };
     }
 
-    /// Returns true when album is not released by a single artist
+    /// Returns the original release date, read from ORIGINALDATE or
+    /// ORIGINALYEAR in Vorbis comments and ID3 TXXX frames, or from
+    /// ID3 TDOR/TORY frames.
+    fn original_date(tags: &gst::TagList) -> Option<ReleaseDate> {
+        let comment = Self::extended_comments(tags)
+            .into_iter()
+            .filter(|(key, _)| matches!(key.as_str(), "originaldate" | "originalyear"))
+            .find_map(|(_, value)| ReleaseDate::parse(&value));
+
+        let frame = || {
+            Self::id3_frames(tags)
+                .into_iter()
+                .filter(|(id, _)| id == b"TDOR" || id == b"TORY")
+                .find_map(|(_, text)| ReleaseDate::parse(&text))
+        };
+
+        comment.or_else(frame)
+    }
+
+    /// Returns the rating in an FMPS_Rating tag (a Vorbis comment or
+    /// an ID3 TXXX frame), or in an ID3 POPM frame, which GStreamer
+    /// reads as the user rating.
+    fn rating_tag(tags: &gst::TagList) -> Option<u8> {
+        let fmps = Self::extended_comments(tags)
+            .into_iter()
+            .filter(|(key, _)| key == "fmps_rating")
+            .find_map(|(_, value)| value.parse().ok());
+
+        match fmps {
+            Some(fraction) => Rating::stars_from_fraction(fraction),
+            None => tags
+                .get::<gst::tags::UserRating>()
+                .and_then(|v| Rating::stars_from_percent(v.get())),
+        }
+    }
+
+    /// Returns the tags GStreamer does not know of in the "KEY=value"
+    /// form as (lowercase key, value) pairs.
+    fn extended_comments(tags: &gst::TagList) -> Vec<(String, String)> {
+        tags.iter_tag::<gst::tags::ExtendedComment>()
+            .filter_map(|v| {
+                let (key, value) = v.get().split_once('=')?;
+                Some((key.trim().to_lowercase(), value.trim().to_string()))
+            })
+            .collect()
+    }
+
+    /// Returns the ID3 text frames GStreamer does not know of as
+    /// (frame ID, text) pairs.
+    fn id3_frames(tags: &gst::TagList) -> Vec<([u8; 4], String)> {
+        // Unknown ID3 frames are passed on as they were in the file:
+        // a four-byte frame ID, six bytes of header, an encoding byte
+        // and the text itself.
+        tags.iter_tag_generic("private-id3v2-frame")
+            .filter_map(|v| v.get::<gst::Sample>().ok())
+            .filter_map(|sample| {
+                let buffer = sample.buffer()?.map_readable().ok()?;
+                let id = buffer.get(..4)?.try_into().ok()?;
+                let text = Self::id3_text(*buffer.get(10)?, buffer.get(11..)?);
+                Some((id, text.trim_matches(char::from(0)).trim().to_string()))
+            })
+            .collect()
+    }
+
+    /// Decodes the text of an ID3 frame in the given encoding:
+    /// 0 for Latin-1, 1 for UTF-16 with a byte order mark, 2 for
+    /// UTF-16BE and 3 for UTF-8.
+    fn id3_text(encoding: u8, data: &[u8]) -> String {
+        match encoding {
+            0 => data.iter().map(|&b| char::from(b)).collect(),
+            1 | 2 => {
+                let big_endian = encoding == 2 || data.starts_with(&[0xfe, 0xff]);
+                let units: Vec<u16> = data
+                    .chunks_exact(2)
+                    .map(|c| match big_endian {
+                        true => u16::from_be_bytes([c[0], c[1]]),
+                        false => u16::from_le_bytes([c[0], c[1]]),
+                    })
+                    .collect();
+                String::from_utf16_lossy(&units)
+                    .trim_start_matches('\u{feff}')
+                    .to_string()
+            }
+            _ => String::from_utf8_lossy(data).to_string(),
+        }
+    }
+
+    /// Returns true if the tags have the compilation flag set, i.e.
+    /// COMPILATION=1 in Vorbis comments or ID3 TXXX frames, an ID3
+    /// TCMP frame or an iTunes `cpil` atom.
+    fn compilation_flag(tags: &gst::TagList) -> bool {
+        let comment = Self::extended_comments(tags)
+            .into_iter()
+            .any(|(key, value)| {
+                matches!(key.as_str(), "compilation" | "itunescompilation")
+                    && matches!(value.to_lowercase().as_str(), "1" | "true" | "yes")
+            });
+
+        let tcmp = Self::id3_frames(tags)
+            .into_iter()
+            .any(|(id, text)| &id == b"TCMP" && text == "1");
+
+        // Unknown MP4 atoms are passed on whole, the flag being the
+        // last byte of the atom.
+        let cpil = tags
+            .iter_tag_generic("private-qt-tag")
+            .filter_map(|v| v.get::<gst::Sample>().ok())
+            .filter(|sample| {
+                sample
+                    .caps()
+                    .and_then(|caps| caps.structure(0))
+                    .is_some_and(|s| s.name() == "application/x-gst-qt-cpil-tag")
+            })
+            .any(|sample| {
+                let buffer = sample.buffer().and_then(|b| b.map_readable().ok());
+                buffer.is_some_and(|b| b.last() == Some(&1))
+            });
+
+        comment || tcmp || cpil
+    }
+
+    /// Marks the song as part of a compilation whose album artist
+    /// is not tagged, see `Playlist::from()`.
+    pub fn set_compilation(&mut self) {
+        if self.has_album_artist {
+            return;
+        }
+
+        self.compilation = true;
+        self.album_artist = match config::get().various_artists.first() {
+            Some(name) => name.to_string(),
+            None => "Various Artists".to_string(),
+        };
+        self.album_artist_sortname = Self::sortname(&self.album_artist);
+        self.update_album_info();
+    }
+
+    /// Returns true if the album artist was read from the tags instead
+    /// of falling back to the artist of this particular track.
+    pub fn has_album_artist(&self) -> bool {
+        self.has_album_artist
+    }
+
+    /// Returns the name used for sorting `name` when no sort tag is
+    /// present. If `Config::ignore_articles` is set, a leading "The",
+    /// "A" or "An" is skipped.
+    fn sortname(name: &str) -> String {
+        match config::get().ignore_articles {
+            true => Self::without_article(name).to_string(),
+            false => name.to_string(),
+        }
+    }
+
+    /// Returns `name` without a leading English article.
+    fn without_article(name: &str) -> &str {
+        for article in &["The ", "A ", "An "] {
+            let n = article.len();
+            if name.len() > n && name.is_char_boundary(n) && name[..n].eq_ignore_ascii_case(article)
+            {
+                return name[n..].trim_start();
+            }
+        }
+
+        name
+    }
+
+    /// Returns the key albums are sorted by on the playlist: album artist,
+    /// date (see `album_date()`) and album title, with names collated
+    /// according to the user's locale.
+    /// `album_info` is included to keep different albums with the same
+    /// sort names apart.
+    pub fn album_sort_key(&self) -> (CollationKey, Option<ReleaseDate>, CollationKey, String) {
+        (
+            CollationKey::from(&self.album_artist_sortname),
+            self.album_date(),
+            CollationKey::from(&self.album_sortname),
+            self.album_info.to_string(),
+        )
+    }
+
+    /// Returns the index of the chapter playing at `position`, if the
+    /// song has chapters.
+    pub fn current_chapter(&self, position: Duration) -> Option<usize> {
+        self.chapters.iter().rposition(|c| c.start <= position)
+    }
+
+    /// Returns true when album is not released by a single artist,
+    /// i.e. when the compilation flag is set, the album artist is one
+    /// of the configured "Various Artists" aliases, or the tracks of
+    /// an album without an album artist have differing artists.
     pub fn part_of_compilation(&self) -> bool {
-        self.album_artist == "Various Artists"
+        self.compilation
     }
 }
 
@@ -166,14 +733,17 @@ impl std::fmt::Display for Song {
         };
 
         // If width is specified and smaller than title length,
-        // we truncate the title.
+        // we truncate the title. The padding is counted in terminal
+        // columns, as wide characters take two of them.
         if let Some(width) = f.width() {
-            if width < title.len() {
-                title.truncate(width - 1);
-                title.push('…');
-            }
-
-            title.fmt(f)
+            title = title.truncate_to_width(width);
+            let padding = width.saturating_sub(title.display_width());
+            let (left, right) = match f.align() {
+                Some(std::fmt::Alignment::Right) => (padding, 0),
+                Some(std::fmt::Alignment::Center) => (padding / 2, padding - padding / 2),
+                _ => (0, padding),
+            };
+            write!(f, "{}{}{}", " ".repeat(left), title, " ".repeat(right))
         } else {
             title.fmt(f)
         }
@@ -185,13 +755,261 @@ mod tests {
     use super::*;
 
     #[test]
-    // If an audio file has no tags, the basename should be used as title
+    // If an audio file has no tags, the title and track number should
+    // be read from the basename
     fn basename_used_for_tagless_files() {
         gst::init().unwrap();
         let path = PathBuf::from("testcases/album_with_no_tags/1. Song 1.mp3")
             .canonicalize()
             .unwrap();
         let song = Song::from(path).unwrap();
-        assert_eq!(song.title, "1. Song 1");
+        assert_eq!(song.title, "Song 1");
+        assert_eq!(song.track_number, 1);
+    }
+
+    #[test]
+    // Untagged files in "Artist/Year - Album" directories get their
+    // album from the directory names, others the directory name
+    fn album_read_from_directories() {
+        gst::init().unwrap();
+
+        let mut song = Song {
+            path: PathBuf::from("/music/Nick Drake/1972 - Pink Moon/01 Pink Moon.ogg"),
+            ..Song::default()
+        };
+        song.read_metadata(&gst::TagList::new());
+        assert_eq!(song.album_info, "Nick Drake: Pink Moon (1972)");
+        assert_eq!(song.artist, "Nick Drake");
+        assert_eq!(song.title, "Pink Moon");
+        assert!(!song.has_album_artist());
+
+        let mut song = Song {
+            path: PathBuf::from("/music/untagged/01 Track.ogg"),
+            ..Song::default()
+        };
+        song.read_metadata(&gst::TagList::new());
+        assert_eq!(song.album_info, "Unknown artist: untagged");
+    }
+
+    #[test]
+    // Tagged files without an album artist tag do not take it from the
+    // directory names, so that compilations are still detected by
+    // their differing track artists, see `Playlist::detect_compilations()`
+    fn tagged_compilation_in_album_directory() {
+        gst::init().unwrap();
+
+        for artist in &["Artist A", "Artist B"] {
+            let mut tags = gst::TagList::new();
+            let t = tags.get_mut().unwrap();
+            t.add::<gst::tags::Album>(&"Hits", gst::TagMergeMode::Append);
+            t.add::<gst::tags::Artist>(artist, gst::TagMergeMode::Append);
+            let mut song = Song {
+                path: PathBuf::from("/music/Music/2001 - Hits/01 Song.ogg"),
+                ..Song::default()
+            };
+            song.read_metadata(&tags);
+            assert_eq!(song.album_artist, *artist);
+            assert!(!song.has_album_artist());
+        }
+    }
+
+    #[test]
+    // The file name fills in missing tags only
+    fn filename_pattern_does_not_override_tags() {
+        gst::init().unwrap();
+
+        let mut tags = gst::TagList::new();
+        let t = tags.get_mut().unwrap();
+        t.add::<gst::tags::Title>(&"Tagged Title", gst::TagMergeMode::Append);
+        let mut song = Song {
+            path: PathBuf::from("/music/Artist - Album - 03 - Title.mp3"),
+            ..Song::default()
+        };
+        song.read_metadata(&tags);
+        assert_eq!(song.title, "Tagged Title");
+        assert_eq!(song.artist, "Artist");
+        assert_eq!(song.album_title, "Album");
+        assert_eq!(song.track_number, 3);
+    }
+
+    #[test]
+    // Non-audio files should be rejected with an error, not a panic
+    fn non_audio_file_rejected() {
+        gst::init().unwrap();
+        let path = PathBuf::from("testcases/generate.sh")
+            .canonicalize()
+            .unwrap();
+        assert!(Song::from(path).is_err());
+    }
+
+    #[test]
+    // Titles are cut between grapheme clusters and padded by terminal
+    // columns, so that wide characters keep the columns aligned
+    fn mixed_script_titles_truncated_by_width() {
+        let song = |title: &str| Song {
+            title: title.to_string(),
+            ..Song::default()
+        };
+
+        // Japanese characters take two columns each
+        assert_eq!(format!("{:6}|", song("日本語のうた")), "日本… |");
+        assert_eq!(format!("{:7}|", song("日本語のうた")), "日本語…|");
+        assert_eq!(format!("{:>8}|", song("日本語")), "  日本語|");
+
+        // "ä" written as "a" and a combining diaeresis is not split
+        let title = song("Ka\u{308}rpa\u{308}nen");
+        assert_eq!(format!("{:4}|", title), "Ka\u{308}r…|");
+        assert_eq!(format!("{:10}|", title), "Ka\u{308}rpa\u{308}nen  |");
+        assert_eq!(format!("{:4}|", song("Mäkelä")), "Mäk…|");
+
+        // Emoji, also those joined into one, take two columns
+        assert_eq!(format!("{:6}|", song("🎸 Rock")), "🎸 Ro…|");
+        assert_eq!(format!("{:5}|", song("👩‍👩‍👧 and")), "👩‍👩‍👧 a…|");
+        assert_eq!(format!("{:6}|", song("Día de 🎉")), "Día d…|");
+        assert_eq!(format!("{:10}|", song("Día de 🎉")), "Día de 🎉 |");
+    }
+
+    #[test]
+    // "The Beatles" should sort under B, but "Theatre" under T
+    fn leading_articles_ignored() {
+        assert_eq!(Song::without_article("The Beatles"), "Beatles");
+        assert_eq!(Song::without_article("the the"), "the");
+        assert_eq!(Song::without_article("An Emotional Fish"), "Emotional Fish");
+        assert_eq!(Song::without_article("Theatre"), "Theatre");
+        assert_eq!(Song::without_article("Björk"), "Björk");
+        assert_eq!(Song::without_article("A"), "A");
+    }
+
+    #[test]
+    // Compilations are detected from the flag tag and from the
+    // "Various Artists" aliases, not just the literal string
+    fn compilation_detected() {
+        gst::init().unwrap();
+
+        let mut tags = gst::TagList::new();
+        let t = tags.get_mut().unwrap();
+        t.add::<gst::tags::AlbumArtist>(&"VA", gst::TagMergeMode::Append);
+        let mut song = Song::default();
+        song.read_metadata(&tags);
+        assert!(song.part_of_compilation());
+
+        let mut tags = gst::TagList::new();
+        let t = tags.get_mut().unwrap();
+        t.add::<gst::tags::Artist>(&"Artist", gst::TagMergeMode::Append);
+        t.add::<gst::tags::Title>(&"Title", gst::TagMergeMode::Append);
+        t.add::<gst::tags::ExtendedComment>(&"COMPILATION=1", gst::TagMergeMode::Append);
+        let mut song = Song::default();
+        song.read_metadata(&tags);
+        assert!(song.part_of_compilation());
+        assert_eq!(format!("{}", song), "Artist: Title");
+
+        let mut tags = gst::TagList::new();
+        let t = tags.get_mut().unwrap();
+        t.add::<gst::tags::AlbumArtist>(&"Vanilla Fudge", gst::TagMergeMode::Append);
+        let mut song = Song::default();
+        song.read_metadata(&tags);
+        assert!(!song.part_of_compilation());
+    }
+
+    #[test]
+    // A track credited to two artists is found under either name
+    fn all_artists_kept() {
+        gst::init().unwrap();
+
+        let mut tags = gst::TagList::new();
+        let t = tags.get_mut().unwrap();
+        t.add::<gst::tags::Artist>(&"Lee Hazlewood", gst::TagMergeMode::Append);
+        t.add::<gst::tags::Artist>(&"Nancy Sinatra", gst::TagMergeMode::Append);
+        t.add::<gst::tags::Genre>(&"Country", gst::TagMergeMode::Append);
+        t.add::<gst::tags::Genre>(&"Pop", gst::TagMergeMode::Append);
+        t.add::<gst::tags::Composer>(&"Lee Hazlewood", gst::TagMergeMode::Append);
+        t.add::<gst::tags::Composer>(&"Billy Strange", gst::TagMergeMode::Append);
+
+        let mut song = Song::default();
+        song.read_metadata(&tags);
+        assert_eq!(song.artist, "Lee Hazlewood, Nancy Sinatra");
+        assert_eq!(song.album_artist, song.artist);
+        assert!(!song.has_album_artist());
+        assert!(song.has_artist("nancy sinatra"));
+        assert!(song.has_artist("Hazlewood"));
+        assert!(!song.has_artist("Frank Sinatra"));
+        assert!(song.has_genre("pop"));
+        assert!(!song.has_genre("Jazz"));
+        assert_eq!(song.composers, ["Lee Hazlewood", "Billy Strange"]);
+        assert!(song.has_composer("billy strange"));
+        assert!(!song.has_composer("Nancy Sinatra"));
+    }
+
+    #[test]
+    // Vorbis comments often only have the plain date tag
+    fn plain_date_and_original_date_read() {
+        gst::init().unwrap();
+
+        let date = glib::Date::new_dmy(26, glib::DateMonth::September, 1969).unwrap();
+        let mut tags = gst::TagList::new();
+        let t = tags.get_mut().unwrap();
+        t.add::<gst::tags::Album>(&"Abbey Road", gst::TagMergeMode::Append);
+        t.add::<gst::tags::AlbumArtist>(&"The Beatles", gst::TagMergeMode::Append);
+        t.add::<gst::tags::Date>(&date, gst::TagMergeMode::Append);
+        t.add::<gst::tags::ExtendedComment>(&"ORIGINALDATE=1969-09", gst::TagMergeMode::Append);
+
+        let mut song = Song::default();
+        song.read_metadata(&tags);
+        assert_eq!(song.date.unwrap().to_string(), "1969-09-26");
+        assert_eq!(song.original_date.unwrap().to_string(), "1969-09");
+        assert_eq!(song.album_info, "The Beatles: Abbey Road (1969)");
+    }
+
+    #[test]
+    // FMPS_Rating is preferred over the POPM user rating
+    fn rating_imported_from_tags() {
+        gst::init().unwrap();
+
+        let mut tags = gst::TagList::new();
+        let t = tags.get_mut().unwrap();
+        t.add::<gst::tags::UserRating>(&100, gst::TagMergeMode::Append);
+        let mut song = Song::default();
+        song.read_metadata(&tags);
+        assert_eq!(song.rating.stars, Some(5));
+
+        let t = tags.get_mut().unwrap();
+        t.add::<gst::tags::ExtendedComment>(&"FMPS_RATING=0.6", gst::TagMergeMode::Append);
+        song.read_metadata(&tags);
+        assert_eq!(song.rating.stars, Some(3));
+    }
+
+    #[test]
+    // Chapters nested in an edition are found and ordered by time
+    fn chapters_read_from_toc() {
+        gst::init().unwrap();
+
+        let chapter = |uid: &str, start: u64, title: Option<&str>| {
+            let mut entry = gst::TocEntry::new(gst::TocEntryType::Chapter, uid);
+            let e = entry.get_mut().unwrap();
+            e.set_start_stop_times((start * 1_000_000_000) as i64, -1);
+            if let Some(title) = title {
+                let mut tags = gst::TagList::new();
+                tags.get_mut()
+                    .unwrap()
+                    .add::<gst::tags::Title>(&title, gst::TagMergeMode::Append);
+                e.set_tags(tags);
+            }
+            entry
+        };
+
+        let mut edition = gst::TocEntry::new(gst::TocEntryType::Edition, "edition");
+        let e = edition.get_mut().unwrap();
+        e.append_sub_entry(chapter("2", 600, Some("Part Two")));
+        e.append_sub_entry(chapter("1", 0, None));
+
+        let mut toc = gst::Toc::new(gst::TocScope::Global);
+        toc.get_mut().unwrap().append_entry(edition);
+
+        let chapters = Chapter::from_toc(&toc);
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(chapters[0].title, "Chapter 1");
+        assert_eq!(chapters[0].start, Duration::from_secs(0));
+        assert_eq!(chapters[1].title, "Part Two");
+        assert_eq!(chapters[1].start, Duration::from_secs(600));
     }
 }
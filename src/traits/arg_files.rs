@@ -1,5 +1,4 @@
 use anyhow::{Context, Result};
-use std::env::Args;
 use std::path::PathBuf;
 
 pub trait ArgFiles {
@@ -7,9 +6,9 @@ pub trait ArgFiles {
 }
 
 /// Ensures all command line arguments are canonical absolute paths
-impl ArgFiles for Args {
+impl ArgFiles for Vec<String> {
     fn files(self) -> Result<Vec<PathBuf>> {
-        self.skip(1)
+        self.into_iter()
             .map(|path| std::fs::canonicalize(&path).context(path))
             .collect()
     }
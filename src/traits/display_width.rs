@@ -0,0 +1,41 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// This trait measures and cuts strings by the number of terminal
+/// columns they take, rather than by bytes or chars.
+pub trait DisplayWidth {
+    fn display_width(&self) -> usize;
+    fn truncate_to_width(&self, width: usize) -> String;
+}
+
+impl DisplayWidth for str {
+    /// Returns the number of terminal columns `self` takes. Combining
+    /// marks take no room, CJK characters and emoji take two columns.
+    fn display_width(&self) -> usize {
+        self.graphemes(true).map(UnicodeWidthStr::width).sum()
+    }
+
+    /// Returns `self` cut to at most `width` columns, with "…" as the
+    /// last character if anything was cut. Grapheme clusters, e.g. a
+    /// letter and its combining accent, are never split.
+    fn truncate_to_width(&self, width: usize) -> String {
+        if self.display_width() <= width {
+            return self.to_string();
+        }
+
+        let mut ret = String::new();
+        let mut used = 0;
+        for grapheme in self.graphemes(true) {
+            used += grapheme.width();
+            if used + 1 > width {
+                break;
+            }
+            ret.push_str(grapheme);
+        }
+
+        if width > 0 {
+            ret.push('…');
+        }
+        ret
+    }
+}
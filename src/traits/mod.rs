@@ -2,6 +2,7 @@
 
 mod arg_files;
 mod audio_playbin;
+mod display_width;
 mod mutex_unwrap;
 mod path_contents;
 mod path_to_uri;
@@ -9,6 +10,7 @@ mod pretty_duration;
 
 pub use arg_files::ArgFiles;
 pub use audio_playbin::AudioPlaybin;
+pub use display_width::DisplayWidth;
 pub use mutex_unwrap::UnwrappedMutex;
 pub use path_contents::PathContents;
 pub use path_to_uri::PathToURI;